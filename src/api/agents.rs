@@ -0,0 +1,40 @@
+use super::{
+    models::{AgentSummary, ThreadSummary},
+    ApiError,
+};
+use crate::SharedState;
+use axum::{extract::State, Json};
+
+/// Agents of every environment, with their threads
+#[tracing::instrument(name = "Api list agents", skip(state))]
+pub async fn list_agents(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<AgentSummary>>, ApiError> {
+    let state_read = state.read().await;
+    let agents = state_read
+        .environments
+        .iter()
+        .flat_map(|env_state| {
+            env_state.agent_configs().iter().filter_map(|config| {
+                let threads = env_state.ui_handler.get_threads_of_agent(&config.id)?;
+                Some(AgentSummary {
+                    env_id: env_state.id().to_owned(),
+                    agent_id: config.id.to_owned(),
+                    provider: config.provider,
+                    model: config.model.to_owned(),
+                    active_thread: threads.active,
+                    threads: threads
+                        .threads
+                        .iter()
+                        .map(|thread| ThreadSummary {
+                            id: thread.id,
+                            name: thread.name.to_owned(),
+                            len: thread.cache.as_ref().len(),
+                        })
+                        .collect(),
+                })
+            })
+        })
+        .collect();
+    Ok(Json(agents))
+}
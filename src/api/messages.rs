@@ -0,0 +1,92 @@
+use super::{
+    models::{ApiEdit, ApiMessage, History, HistoryMessage},
+    ApiError, ApiJson, ApiPath,
+};
+use crate::{
+    client::Client,
+    espx_env::{
+        ui_listeners::{message_hash, CacheEdit},
+        EnvironmentState,
+    },
+    patches::{confirm_edit, edit_failed},
+    AppError, SharedState,
+};
+use axum::{extract::State, Json};
+
+fn thread_history(
+    env_state: &EnvironmentState,
+    agent_id: &str,
+    thread_id: u64,
+) -> Result<History, AppError> {
+    let thread = env_state
+        .ui_handler
+        .get_thread(agent_id, thread_id)
+        .ok_or(AppError::NotFound(format!(
+            "No thread {} for agent '{}'",
+            thread_id, agent_id
+        )))?;
+    let messages = thread
+        .cache
+        .as_ref()
+        .iter()
+        .enumerate()
+        .map(|(index, message)| HistoryMessage {
+            index,
+            message: ApiMessage::from(message),
+            hash: message_hash(message),
+            pinned: thread.pinned.contains(&index),
+        })
+        .collect();
+    Ok(History {
+        env_id: env_state.id().to_owned(),
+        agent_id: agent_id.to_owned(),
+        thread_id,
+        messages,
+        can_undo: thread.history.can_undo(),
+        can_redo: thread.history.can_redo(),
+    })
+}
+
+#[tracing::instrument(name = "Api thread history", skip(state))]
+pub async fn history(
+    State(state): State<SharedState>,
+    ApiPath((env_id, agent_id, thread_id)): ApiPath<(String, String, u64)>,
+) -> Result<Json<History>, ApiError> {
+    let state_read = state.read().await;
+    Ok(Json(thread_history(
+        state_read.env_state(&env_id)?,
+        &agent_id,
+        thread_id,
+    )?))
+}
+
+/// Makes the edit and returns the thread's history as the agent has it afterwards. An edit whose
+/// `expected` hash doesn't match the message it's aimed at is a conflict.
+#[tracing::instrument(name = "Api edit thread", skip(state))]
+pub async fn edit(
+    State(state): State<SharedState>,
+    client: Client,
+    ApiPath((env_id, agent_id, thread_id)): ApiPath<(String, String, u64)>,
+    ApiJson(edit): ApiJson<ApiEdit>,
+) -> Result<Json<History>, ApiError> {
+    let edit = CacheEdit {
+        agent_id: agent_id.to_owned(),
+        thread_id,
+        edit: edit.op.try_into()?,
+        expected: edit.expected,
+    };
+    let ack = state
+        .write()
+        .await
+        .env_state_mut(&env_id)?
+        .ui_handler
+        .push_to_changes(edit, Some(client.as_str()))
+        .map_err(|err| edit_failed(err).context("Error updating cache"))?;
+    confirm_edit(&state, &env_id, &agent_id, ack).await?;
+    let state_read = state.read().await;
+    Ok(Json(thread_history(
+        state_read.env_state(&env_id)?,
+        &agent_id,
+        thread_id,
+    )?))
+}
@@ -0,0 +1,62 @@
+//! Json apis for scripts and other apps rather than htmx: our own, versioned under `/api/v1`,
+//! and OpenAI's chat completions under `/v1`
+pub mod agents;
+pub mod messages;
+pub mod models;
+pub mod openai;
+pub mod prompt;
+
+use crate::AppError;
+use axum::{
+    extract::Path,
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_macros::{FromRequest, FromRequestParts};
+use models::ApiErrorBody;
+
+/// OpenAPI 3 description of every `/api/v1` endpoint
+const OPENAPI: &str = include_str!("openapi.json");
+
+/// An `AppError` answered with json instead of an htmx fragment
+#[derive(Debug)]
+pub struct ApiError(pub AppError);
+
+/// `Json` whose rejection is answered with an `ApiError`, a body that isn't valid json or doesn't
+/// match is a bad request
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// `Path` whose rejection is answered with an `ApiError`
+#[derive(FromRequestParts)]
+#[from_request(via(Path), rejection(ApiError))]
+pub struct ApiPath<T>(pub T);
+
+impl<E: Into<AppError>> From<E> for ApiError {
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
+
+impl ApiError {
+    pub fn body(&self) -> ApiErrorBody {
+        ApiErrorBody {
+            status: self.0.status_code().as_u16(),
+            message: self.0.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        self.0.log();
+        let body = serde_json::json!({ "error": self.body() });
+        (self.0.status_code(), Json(body)).into_response()
+    }
+}
+
+pub async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
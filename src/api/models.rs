@@ -0,0 +1,153 @@
+use crate::{
+    espx_env::{config::Provider, ui_listeners::StackEdit},
+    AppError,
+};
+use espionox::agents::memory::{Message, MessageRole};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize)]
+pub struct ApiErrorBody {
+    pub status: u16,
+    pub message: String,
+}
+
+/// A message as the api takes and gives it, with a lowercase `system`, `user` or `assistant` role
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl From<&Message> for ApiMessage {
+    fn from(message: &Message) -> Self {
+        Self {
+            role: message.role.to_string(),
+            content: message.content.to_owned(),
+        }
+    }
+}
+
+impl TryFrom<ApiMessage> for Message {
+    type Error = AppError;
+    fn try_from(message: ApiMessage) -> Result<Self, Self::Error> {
+        let role = parse_role(message.role)?;
+        Ok(Message {
+            role,
+            content: message.content,
+        })
+    }
+}
+
+fn parse_role(role: String) -> Result<MessageRole, AppError> {
+    MessageRole::try_from(role).map_err(AppError::bad_request)
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentSummary {
+    pub env_id: String,
+    pub agent_id: String,
+    pub provider: Provider,
+    pub model: String,
+    /// Thread the agent is replying in
+    pub active_thread: u64,
+    pub threads: Vec<ThreadSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadSummary {
+    pub id: u64,
+    pub name: String,
+    pub len: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryMessage {
+    pub index: usize,
+    #[serde(flatten)]
+    pub message: ApiMessage,
+    /// `message_hash` of the message, sent back as an edit's `expected`
+    pub hash: String,
+    pub pinned: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct History {
+    pub env_id: String,
+    pub agent_id: String,
+    pub thread_id: u64,
+    pub messages: Vec<HistoryMessage>,
+    pub can_undo: bool,
+    pub can_redo: bool,
+}
+
+/// Body of an edit, `expected` is the hash of the message it's aimed at as the client last saw it
+#[derive(Debug, Deserialize)]
+pub struct ApiEdit {
+    #[serde(flatten)]
+    pub op: EditOp,
+    #[serde(default)]
+    pub expected: Option<String>,
+}
+
+/// The `StackEdit`s a client may make, by `op`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum EditOp {
+    Edit { index: usize, content: String },
+    Remove { index: usize },
+    Push { message: ApiMessage },
+    Insert { index: usize, message: ApiMessage },
+    Move { from: usize, to: usize },
+    ChangeRole { index: usize, role: String },
+    Truncate { len: usize },
+    Clear,
+}
+
+impl TryFrom<EditOp> for StackEdit {
+    type Error = AppError;
+    fn try_from(op: EditOp) -> Result<Self, Self::Error> {
+        Ok(match op {
+            EditOp::Edit { index, content } => StackEdit::EditMessageInCache {
+                idx: index,
+                new_text: content,
+            },
+            EditOp::Remove { index } => StackEdit::RemoveMessageInCache { idx: index },
+            EditOp::Push { message } => StackEdit::PushMessageToCache {
+                message: message.try_into()?,
+            },
+            EditOp::Insert { index, message } => StackEdit::InsertAt {
+                idx: index,
+                message: message.try_into()?,
+            },
+            EditOp::Move { from, to } => StackEdit::Move { from, to },
+            EditOp::ChangeRole { index, role } => StackEdit::ChangeRole {
+                idx: index,
+                role: parse_role(role)?,
+            },
+            EditOp::Truncate { len } => StackEdit::Truncate { len },
+            EditOp::Clear => StackEdit::Clear,
+        })
+    }
+}
+
+/// Prompts the agent in its active thread, `stream` answers with server sent events
+#[derive(Debug, Deserialize)]
+pub struct PromptRequest {
+    pub content: String,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptReply {
+    #[serde(flatten)]
+    pub message: ApiMessage,
+    /// The reply was cancelled and is only as far as it got
+    pub cancelled: bool,
+}
+
+/// Data of each `token` event of a streamed reply
+#[derive(Debug, Serialize)]
+pub struct TokenEvent {
+    pub delta: String,
+}
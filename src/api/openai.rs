@@ -0,0 +1,395 @@
+//! OpenAI compatible chat completions, so OpenAI SDKs and tools can talk to the configured agents.
+//! The `model` of a request is the id of the agent, or `<env_id>/<agent_id>` outside the `default`
+//! environment.
+use crate::{
+    client::Client,
+    espx_env::{agent_link::AgentLink, config::DEFAULT_ENV_ID},
+    websocket::completion::PendingReply,
+    AppError, AppState, SharedState,
+};
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use axum_macros::FromRequest;
+use espionox::agents::memory::{Message, MessageRole, MessageStack};
+use futures::channel::mpsc;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Fields OpenAI takes besides these, like `temperature`, are ignored, the agent's own params apply
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: ChatContent,
+}
+
+/// Newer clients may send a message's content as a list of parts, only text parts are kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+impl From<ChatContent> for String {
+    fn from(content: ChatContent) -> Self {
+        match content {
+            ChatContent::Text(text) => text,
+            ChatContent::Parts(parts) => parts
+                .into_iter()
+                .filter_map(|part| part.text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl TryFrom<ChatMessage> for Message {
+    type Error = AppError;
+    fn try_from(message: ChatMessage) -> Result<Self, Self::Error> {
+        let role = MessageRole::try_from(message.role).map_err(AppError::bad_request)?;
+        Ok(Message {
+            role,
+            content: message.content.into(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatCompletion {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<Choice>,
+    pub usage: ChatUsage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Choice {
+    pub index: usize,
+    pub message: ChatMessage,
+    pub finish_reason: &'static str,
+}
+
+/// Estimated like the usage of the agent's own completions
+#[derive(Debug, Serialize)]
+pub struct ChatUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: &'static str,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChunkChoice {
+    pub index: usize,
+    pub delta: Delta,
+    pub finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// An `AppError` in the shape OpenAI clients expect
+#[derive(Debug)]
+pub struct OpenAiError(pub AppError);
+
+/// `Json` whose rejection is answered with an `OpenAiError`
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(OpenAiError))]
+pub struct OpenAiJson<T>(pub T);
+
+impl<E: Into<AppError>> From<E> for OpenAiError {
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
+
+impl OpenAiError {
+    fn body(&self) -> serde_json::Value {
+        let kind = match self.0 {
+            AppError::Internal(_) => "server_error",
+            _ => "invalid_request_error",
+        };
+        serde_json::json!({
+            "error": {
+                "message": self.0.to_string(),
+                "type": kind,
+                "param": null,
+                "code": null,
+            }
+        })
+    }
+}
+
+impl IntoResponse for OpenAiError {
+    fn into_response(self) -> Response {
+        self.0.log();
+        (self.0.status_code(), Json(self.body())).into_response()
+    }
+}
+
+/// The thread a chat completion runs on, and the one the agent goes back to once it's done
+struct CompletionThread {
+    own: u64,
+    previous: u64,
+}
+
+impl CompletionThread {
+    /// Switches the agent back, unless it was switched to another thread meanwhile, and deletes
+    /// the completion's thread. The reply has to be in the completion's thread first, or the
+    /// switch would carry it over. Failing to is only logged, the completion went through all the
+    /// same.
+    async fn leave(
+        self,
+        state: &SharedState,
+        env_id: &str,
+        agent_id: &str,
+        link: Option<&AgentLink>,
+    ) {
+        let result = async {
+            if let Some(link) = link {
+                link.sync().await?;
+            }
+            let mut state = state.write().await;
+            let ui_handler = &mut state.env_state_mut(env_id)?.ui_handler;
+            let active = ui_handler
+                .get_threads_of_agent(agent_id)
+                .map(|threads| threads.active);
+            if active == Some(self.own) && ui_handler.get_thread(agent_id, self.previous).is_some()
+            {
+                ui_handler.switch_thread(agent_id, self.previous)?;
+            }
+            ui_handler.delete_thread(agent_id, self.own)?;
+            Ok::<_, AppError>(())
+        }
+        .await;
+        if let Err(err) = result {
+            err.context("Could not leave the completion's thread").log();
+        }
+    }
+}
+
+/// Ids of the environment and agent a `model` stands for
+fn find_agent(state: &AppState, model: &str) -> Result<(String, String), AppError> {
+    let (env_id, agent_id) = match model.split_once('/') {
+        Some((env_id, agent_id)) => (env_id, agent_id),
+        None => (DEFAULT_ENV_ID, model),
+    };
+    let env_state = state.env_state(env_id)?;
+    match env_state.agent_configs().iter().any(|c| c.id == agent_id) {
+        true => Ok((env_id.to_owned(), agent_id.to_owned())),
+        false => Err(AppError::not_found(format!(
+            "The model '{}' does not exist",
+            model
+        ))),
+    }
+}
+
+/// `model` of each agent, as `GET /v1/models` lists them
+#[tracing::instrument(name = "OpenAi list models", skip(state))]
+pub async fn list_models(State(state): State<SharedState>) -> Json<serde_json::Value> {
+    let state_read = state.read().await;
+    let models: Vec<_> = state_read
+        .environments
+        .iter()
+        .flat_map(|env_state| {
+            env_state.agent_configs().iter().map(|config| {
+                let id = match env_state.id() {
+                    DEFAULT_ENV_ID => config.id.to_owned(),
+                    env_id => format!("{}/{}", env_id, config.id),
+                };
+                serde_json::json!({
+                    "id": id,
+                    "object": "model",
+                    "created": 0,
+                    "owned_by": "bureau",
+                })
+            })
+        })
+        .collect();
+    Json(serde_json::json!({ "object": "list", "data": models }))
+}
+
+/// Each completion gets a thread of its own, holding the agent's system prompt followed by the
+/// request's messages but the last, which it's prompted with. The agent is back on the thread it
+/// was on once the reply is in and the completion's thread is deleted, so the conversation in the
+/// UI is left as it was.
+#[tracing::instrument(name = "OpenAi chat completion", skip(state, request), fields(model = %request.model))]
+pub async fn chat_completions(
+    State(state): State<SharedState>,
+    client: Client,
+    OpenAiJson(request): OpenAiJson<ChatCompletionRequest>,
+) -> Result<Response, OpenAiError> {
+    let mut messages = request
+        .messages
+        .into_iter()
+        .map(Message::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    let prompt = match messages.pop() {
+        Some(prompt) if prompt.role == MessageRole::User => prompt,
+        _ => {
+            return Err(AppError::bad_request("The last message must be from the user").into());
+        }
+    };
+
+    let id = format!("chatcmpl-{}", Uuid::new_v4().simple());
+    let (env_id, agent_id, mut completion, thread) = {
+        let mut state_write = state.write().await;
+        let (env_id, agent_id) = find_agent(&state_write, &request.model)?;
+        let completion = state_write.in_flight.start(&env_id, &agent_id)?;
+        let env_state = state_write.env_state_mut(&env_id)?;
+        let config = env_state
+            .agent_configs()
+            .iter()
+            .find(|c| c.id == agent_id)
+            .ok_or(AppError::not_found(format!(
+                "No agent with id '{}'",
+                agent_id
+            )))?;
+        let mut cache: MessageStack = config.build_agent()?.cache;
+        messages.into_iter().for_each(|message| cache.push(message));
+        let previous = env_state
+            .ui_handler
+            .get_threads_of_agent(&agent_id)
+            .map(|threads| threads.active)
+            .unwrap_or_default();
+        let own = env_state
+            .ui_handler
+            .create_thread(&agent_id, &id, cache)
+            .map_err(|err| {
+                AppError::from(err).context("Could not start the completion's thread")
+            })?;
+        (
+            env_id,
+            agent_id,
+            completion,
+            CompletionThread { own, previous },
+        )
+    };
+    let reply = match PendingReply::start(&state, &env_id, &agent_id, prompt, &client).await {
+        Ok(reply) => reply,
+        Err(err) => {
+            thread.leave(&state, &env_id, &agent_id, None).await;
+            return Err(err.into());
+        }
+    };
+    let link = reply.link().clone();
+
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    let model = request.model;
+
+    if !request.stream {
+        let result = reply.stream(&mut completion, |_| async {}).await;
+        thread.leave(&state, &env_id, &agent_id, Some(&link)).await;
+        let reply = result?;
+        let usage = reply.usage.unwrap_or_default();
+        let completion = ChatCompletion {
+            id,
+            object: "chat.completion",
+            created,
+            model,
+            choices: vec![Choice {
+                index: 0,
+                message: ChatMessage {
+                    role: "assistant".to_owned(),
+                    content: ChatContent::Text(reply.content),
+                },
+                finish_reason: "stop",
+            }],
+            usage: ChatUsage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: usage.total_tokens(),
+            },
+        };
+        return Ok(Json(completion).into_response());
+    }
+
+    let chunk = move |delta: Delta, finish_reason: Option<&'static str>| {
+        Event::default().json_data(ChatCompletionChunk {
+            id: id.to_owned(),
+            object: "chat.completion.chunk",
+            created,
+            model: model.to_owned(),
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+        })
+    };
+    let (tx, rx) = mpsc::unbounded::<Result<Event, serde_json::Error>>();
+    // Streamed to the end even if the client goes away, so the reply still makes the cache
+    tokio::spawn(async move {
+        let role = Delta {
+            role: Some("assistant"),
+            content: Some(String::new()),
+        };
+        let _ = tx.unbounded_send(chunk(role, None));
+        let result = reply
+            .stream(&mut completion, |token| {
+                let delta = Delta {
+                    content: Some(token),
+                    ..Default::default()
+                };
+                let _ = tx.unbounded_send(chunk(delta, None));
+                async {}
+            })
+            .await;
+        thread.leave(&state, &env_id, &agent_id, Some(&link)).await;
+        drop(completion);
+        let last = match result {
+            Ok(_) => chunk(Delta::default(), Some("stop")),
+            Err(err) => {
+                let err = OpenAiError(err);
+                err.0.log();
+                Event::default().json_data(err.body())
+            }
+        };
+        let _ = tx.unbounded_send(last);
+        let _ = tx.unbounded_send(Ok(Event::default().data("[DONE]")));
+    });
+    Ok(Sse::new(rx)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
@@ -0,0 +1,77 @@
+use super::{
+    models::{ApiMessage, PromptReply, PromptRequest, TokenEvent},
+    ApiError, ApiJson, ApiPath,
+};
+use crate::{
+    client::Client,
+    websocket::completion::{PendingReply, StreamedReply},
+    SharedState,
+};
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use espionox::agents::memory::Message;
+use futures::channel::mpsc;
+
+impl From<StreamedReply> for PromptReply {
+    fn from(reply: StreamedReply) -> Self {
+        Self {
+            message: ApiMessage::from(&Message::new_assistant(&reply.content)),
+            cancelled: reply.cancelled,
+        }
+    }
+}
+
+/// Prompts the agent in its active thread. Answers with the whole reply, or with `token` events
+/// ending in a `done` event carrying the reply when `stream` is set. A stream that fails after
+/// it started ends in an `error` event instead.
+#[tracing::instrument(name = "Api prompt", skip(state, request))]
+pub async fn prompt(
+    State(state): State<SharedState>,
+    client: Client,
+    ApiPath((env_id, agent_id)): ApiPath<(String, String)>,
+    ApiJson(request): ApiJson<PromptRequest>,
+) -> Result<Response, ApiError> {
+    let mut completion = state.read().await.in_flight.start(&env_id, &agent_id)?;
+    let prompt = Message::new_user(&request.content);
+    let reply = PendingReply::start(&state, &env_id, &agent_id, prompt, &client).await?;
+    if !request.stream {
+        let reply = reply.stream(&mut completion, |_| async {}).await?;
+        return Ok(Json(PromptReply::from(reply)).into_response());
+    }
+
+    let (tx, rx) = mpsc::unbounded::<Result<Event, serde_json::Error>>();
+    // Streamed to the end even if the client goes away, so the reply still makes the cache
+    tokio::spawn(async move {
+        let token_tx = tx.clone();
+        let result = reply
+            .stream(&mut completion, |delta| {
+                let _ = token_tx.unbounded_send(
+                    Event::default()
+                        .event("token")
+                        .json_data(TokenEvent { delta }),
+                );
+                async {}
+            })
+            .await;
+        let event = match result {
+            Ok(reply) => Event::default()
+                .event("done")
+                .json_data(PromptReply::from(reply)),
+            Err(err) => {
+                let err = ApiError(err);
+                err.0.log();
+                Event::default().event("error").json_data(err.body())
+            }
+        };
+        let _ = tx.unbounded_send(event);
+    });
+    Ok(Sse::new(rx)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
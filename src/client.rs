@@ -0,0 +1,68 @@
+use crate::SharedState;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+/// Who sent a request, as far as the server can tell: the peer's address, or the address a
+/// trusted proxy got the request from as `X-Forwarded-For` tells. Recorded in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client(pub String);
+
+impl Client {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<SharedState> for Client {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &SharedState,
+    ) -> Result<Self, Self::Rejection> {
+        // Only there when the app is served with `into_make_service_with_connect_info`
+        let Some(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+        else {
+            return Ok(Self(String::from("unknown")));
+        };
+        let trusted_proxies = state.read().await.trusted_proxies.clone();
+        if !trusted_proxies.contains(&peer) {
+            return Ok(Self(peer.to_string()));
+        }
+        let is_trusted = |addr: &str| {
+            addr.parse::<IpAddr>()
+                .is_ok_and(|ip| trusted_proxies.contains(&ip))
+        };
+
+        // Each proxy appends the address it got the request from, anything left of the last
+        // untrusted one could have been made up by the client
+        let forwarded: Vec<&str> = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .collect();
+        let client = forwarded
+            .iter()
+            .rev()
+            .find(|addr| !is_trusted(addr))
+            .or(forwarded.first())
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| peer.to_string());
+        Ok(Self(client))
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::anyhow;
+use rusqlite::{params, Connection};
+
+pub(super) struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every schema change goes at the end of this list with the next version number.
+/// Never edit a migration once it has shipped, add a new one instead.
+pub(super) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create agent caches",
+        sql: "CREATE TABLE agent_caches (
+            agent_id TEXT PRIMARY KEY NOT NULL,
+            cache TEXT NOT NULL,
+            updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );",
+    },
+    Migration {
+        version: 2,
+        description: "Move agent caches into named threads",
+        sql: "CREATE TABLE threads (
+            agent_id TEXT NOT NULL,
+            thread_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            cache TEXT NOT NULL,
+            active INTEGER NOT NULL DEFAULT 0,
+            updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (agent_id, thread_id)
+        );
+        INSERT INTO threads (agent_id, thread_id, name, cache, active, updated_at)
+            SELECT agent_id, 1, 'main', cache, 1, updated_at FROM agent_caches;
+        DROP TABLE agent_caches;",
+    },
+    Migration {
+        version: 3,
+        description: "Scope threads by environment",
+        sql: "CREATE TABLE env_threads (
+            env_id TEXT NOT NULL,
+            agent_id TEXT NOT NULL,
+            thread_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            cache TEXT NOT NULL,
+            active INTEGER NOT NULL DEFAULT 0,
+            updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (env_id, agent_id, thread_id)
+        );
+        INSERT INTO env_threads (env_id, agent_id, thread_id, name, cache, active, updated_at)
+            SELECT 'default', agent_id, thread_id, name, cache, active, updated_at FROM threads;
+        DROP TABLE threads;
+        ALTER TABLE env_threads RENAME TO threads;",
+    },
+    Migration {
+        version: 4,
+        description: "Keep variants of regenerated replies",
+        sql: "ALTER TABLE threads ADD COLUMN variants TEXT NOT NULL DEFAULT '{}';",
+    },
+    Migration {
+        version: 5,
+        description: "Count token usage of each thread",
+        sql: "CREATE TABLE usage (
+            env_id TEXT NOT NULL,
+            agent_id TEXT NOT NULL,
+            thread_id INTEGER NOT NULL,
+            completions INTEGER NOT NULL DEFAULT 0,
+            prompt_tokens INTEGER NOT NULL DEFAULT 0,
+            completion_tokens INTEGER NOT NULL DEFAULT 0,
+            updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (env_id, agent_id, thread_id)
+        );",
+    },
+    Migration {
+        version: 6,
+        description: "Pin messages to the context window",
+        sql: "ALTER TABLE threads ADD COLUMN pinned TEXT NOT NULL DEFAULT '[]';",
+    },
+    Migration {
+        version: 7,
+        description: "Log edits and completions",
+        sql: "CREATE TABLE audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            env_id TEXT NOT NULL,
+            agent_id TEXT NOT NULL,
+            thread_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            before TEXT,
+            after TEXT,
+            client TEXT
+        );
+        CREATE INDEX audit_log_agent ON audit_log (env_id, agent_id);
+        CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'The audit log is append-only'); END;
+        CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+            BEGIN SELECT RAISE(ABORT, 'The audit log is append-only'); END;",
+    },
+    Migration {
+        version: 8,
+        description: "Never hand out a thread id twice",
+        sql: "CREATE TABLE thread_ids (
+            env_id TEXT NOT NULL,
+            agent_id TEXT NOT NULL,
+            last_id INTEGER NOT NULL,
+            PRIMARY KEY (env_id, agent_id)
+        );
+        INSERT INTO thread_ids (env_id, agent_id, last_id)
+            SELECT env_id, agent_id, MAX(thread_id) FROM (
+                SELECT env_id, agent_id, thread_id FROM threads
+                UNION ALL SELECT env_id, agent_id, thread_id FROM usage
+                UNION ALL SELECT env_id, agent_id, thread_id FROM audit_log
+            ) GROUP BY env_id, agent_id;",
+    },
+    Migration {
+        version: 9,
+        description: "Start the audit log of a re-created agent afresh",
+        sql: "ALTER TABLE thread_ids ADD COLUMN first_id INTEGER NOT NULL DEFAULT 1;",
+    },
+];
+
+/// Applies every migration newer than the database's current version, each in its own transaction
+#[tracing::instrument(name = "Run database migrations", skip(conn))]
+pub(super) fn run(conn: &mut Connection) -> Result<(), anyhow::Error> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY NOT NULL,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );",
+    )?;
+    let current: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )?;
+
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+    if current > latest {
+        return Err(anyhow!(
+            "Database schema is at version {}, but this build only knows up to version {}",
+            current,
+            latest
+        ));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        tracing::info!(
+            "Applying migration {}: {}",
+            migration.version,
+            migration.description
+        );
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, description) VALUES (?1, ?2)",
+            params![migration.version, migration.description],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
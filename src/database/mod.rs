@@ -0,0 +1,321 @@
+mod migrations;
+
+use crate::espx_env::{
+    audit::{AuditEntry, AuditEvent, AuditFilter},
+    threads::{AgentThreads, EditHistory, Thread},
+    usage::Usage,
+};
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use std::sync::{Arc, Mutex};
+
+/// Cheaply clonable handle to the SQLite store, every clone shares one connection
+#[derive(Debug, Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    /// Opens (or creates) the database at `path` and brings its schema up to date
+    #[tracing::instrument(name = "Connect to database")]
+    pub fn connect(path: &str) -> Result<Self, anyhow::Error> {
+        let mut conn =
+            Connection::open(path).with_context(|| format!("Could not open database {}", path))?;
+        migrations::run(&mut conn).context("Could not migrate database")?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Inserts the thread or updates its name, cache, variants and pins
+    #[tracing::instrument(name = "Save thread", skip(self, thread), fields(thread_id = thread.id))]
+    pub fn save_thread(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+        thread: &Thread,
+    ) -> Result<(), anyhow::Error> {
+        let json = serde_json::to_string(&thread.cache)?;
+        let variants = serde_json::to_string(&thread.variants)?;
+        let pinned = serde_json::to_string(&thread.pinned)?;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO threads (env_id, agent_id, thread_id, name, cache, variants, pinned)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(env_id, agent_id, thread_id) DO UPDATE SET
+                name = excluded.name, cache = excluded.cache, variants = excluded.variants,
+                pinned = excluded.pinned, updated_at = CURRENT_TIMESTAMP",
+            params![
+                env_id,
+                agent_id,
+                thread.id as i64,
+                thread.name,
+                json,
+                variants,
+                pinned
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_active_thread(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+        thread_id: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE threads SET active = (thread_id = ?3) WHERE env_id = ?1 AND agent_id = ?2",
+            params![env_id, agent_id, thread_id as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Returns `None` if nothing was ever saved for the agent
+    pub fn load_threads(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+    ) -> Result<Option<AgentThreads>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT thread_id, name, cache, active, variants, pinned FROM threads
+             WHERE env_id = ?1 AND agent_id = ?2 ORDER BY thread_id",
+        )?;
+        let rows = stmt.query_map(params![env_id, agent_id], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+            ))
+        })?;
+
+        let mut threads = vec![];
+        let mut active = None;
+        for row in rows {
+            let (id, name, json, is_active, variants, pinned) = row?;
+            let corrupt = || {
+                format!(
+                    "Stored cache of thread {} of agent '{}' in environment '{}' is corrupt",
+                    id, agent_id, env_id
+                )
+            };
+            let cache = serde_json::from_str(&json).with_context(corrupt)?;
+            let variants = serde_json::from_str(&variants).with_context(corrupt)?;
+            let pinned = serde_json::from_str(&pinned).with_context(corrupt)?;
+            if is_active {
+                active = Some(id as u64);
+            }
+            threads.push(Thread {
+                id: id as u64,
+                name,
+                cache,
+                variants,
+                pinned,
+                history: EditHistory::default(),
+            });
+        }
+
+        match threads.first() {
+            Some(first) => Ok(Some(AgentThreads {
+                active: active.unwrap_or(first.id),
+                threads,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Takes the agent's next thread id, never below `at_least`. Ids of deleted threads aren't
+    /// handed out again, so new threads don't inherit their usage or audit log.
+    pub fn next_thread_id(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+        at_least: u64,
+    ) -> Result<u64, anyhow::Error> {
+        let id: i64 = self.conn.lock().unwrap().query_row(
+            "INSERT INTO thread_ids (env_id, agent_id, last_id) VALUES (?1, ?2, ?3)
+             ON CONFLICT(env_id, agent_id) DO UPDATE SET
+                last_id = MAX(last_id + 1, excluded.last_id)
+             RETURNING last_id",
+            params![env_id, agent_id, at_least as i64],
+            |row| row.get(0),
+        )?;
+        Ok(id as u64)
+    }
+
+    /// Takes the id of a new agent's first thread, its audit log starts there. An agent by the
+    /// same id that was deleted keeps its entries under the ids before.
+    pub fn first_thread_id(&self, env_id: &str, agent_id: &str) -> Result<u64, anyhow::Error> {
+        let id: i64 = self.conn.lock().unwrap().query_row(
+            "INSERT INTO thread_ids (env_id, agent_id, last_id, first_id) VALUES (?1, ?2, 1, 1)
+             ON CONFLICT(env_id, agent_id) DO UPDATE SET
+                last_id = last_id + 1,
+                first_id = last_id + 1
+             RETURNING last_id",
+            params![env_id, agent_id],
+            |row| row.get(0),
+        )?;
+        Ok(id as u64)
+    }
+
+    pub fn delete_thread(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+        thread_id: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM threads WHERE env_id = ?1 AND agent_id = ?2 AND thread_id = ?3",
+            params![env_id, agent_id, thread_id as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Usage of deleted threads is kept, only deleting the agent forgets it. Its audit log and
+    /// thread ids are kept either way.
+    pub fn delete_agent(&self, env_id: &str, agent_id: &str) -> Result<(), anyhow::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM threads WHERE env_id = ?1 AND agent_id = ?2",
+            params![env_id, agent_id],
+        )?;
+        tx.execute(
+            "DELETE FROM usage WHERE env_id = ?1 AND agent_id = ?2",
+            params![env_id, agent_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Adds to the thread's totals
+    #[tracing::instrument(name = "Add usage", skip(self))]
+    pub fn add_usage(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+        thread_id: u64,
+        usage: &Usage,
+    ) -> Result<(), anyhow::Error> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO usage (env_id, agent_id, thread_id, completions, prompt_tokens, completion_tokens)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(env_id, agent_id, thread_id) DO UPDATE SET
+                completions = completions + excluded.completions,
+                prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                completion_tokens = completion_tokens + excluded.completion_tokens,
+                updated_at = CURRENT_TIMESTAMP",
+            params![
+                env_id,
+                agent_id,
+                thread_id as i64,
+                usage.completions as i64,
+                usage.prompt_tokens as i64,
+                usage.completion_tokens as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Totals of each of the agent's threads, by thread id
+    pub fn load_usage(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+    ) -> Result<Vec<(u64, Usage)>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT thread_id, completions, prompt_tokens, completion_tokens FROM usage
+             WHERE env_id = ?1 AND agent_id = ?2 ORDER BY thread_id",
+        )?;
+        let rows = stmt.query_map(params![env_id, agent_id], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as u64,
+                Usage {
+                    completions: row.get::<_, i64>(1)? as u64,
+                    prompt_tokens: row.get::<_, i64>(2)? as u64,
+                    completion_tokens: row.get::<_, i64>(3)? as u64,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Entries are never updated or deleted, the table refuses it
+    #[tracing::instrument(name = "Append to audit log", skip(self, event), fields(kind = %event.kind))]
+    pub fn append_audit(&self, env_id: &str, event: &AuditEvent) -> Result<(), anyhow::Error> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO audit_log (env_id, agent_id, thread_id, kind, before, after, client)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                env_id,
+                event.agent_id,
+                event.thread_id as i64,
+                event.kind.as_str(),
+                event.before,
+                event.after,
+                event.client
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The entries of the agent matching the filter, newest first, leaving out those of a
+    /// deleted agent by the same id
+    pub fn load_audit(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+        filter: &AuditFilter,
+    ) -> Result<Vec<AuditEntry>, anyhow::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, at, thread_id, kind, before, after, client FROM audit_log
+             WHERE env_id = ?1 AND agent_id = ?2
+                AND thread_id >= COALESCE(
+                    (SELECT first_id FROM thread_ids WHERE env_id = ?1 AND agent_id = ?2), 1)
+                AND (?3 IS NULL OR kind = ?3) AND (?4 IS NULL OR client = ?4)
+             ORDER BY id DESC LIMIT ?5",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                env_id,
+                agent_id,
+                filter.kind.map(|kind| kind.as_str()),
+                filter.client,
+                filter.limit()
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            },
+        )?;
+        let mut entries = vec![];
+        for row in rows {
+            let (id, at, thread_id, kind, before, after, client) = row?;
+            entries.push(AuditEntry {
+                id: id as u64,
+                at,
+                event: AuditEvent {
+                    agent_id: agent_id.to_owned(),
+                    thread_id: thread_id as u64,
+                    kind: kind.parse()?,
+                    before,
+                    after,
+                    client,
+                },
+            });
+        }
+        Ok(entries)
+    }
+}
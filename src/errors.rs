@@ -0,0 +1,140 @@
+use askama::Template;
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use espionox::{
+    agents::AgentError,
+    environment::{EnvError, EnvHandleError},
+};
+use std::fmt::{Display, Formatter};
+
+/// Everything a handler can fail with. Rendered as an htmx fragment for http requests and as an
+/// out of band error frame for websocket requests.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request was malformed or asked for something invalid
+    BadRequest(String),
+    /// No environment, agent, thread or message by the requested id
+    NotFound(String),
+    /// What the request was aimed at changed since the client last saw it
+    Conflict(String),
+    /// Espionox, the database or a template failed
+    Internal(anyhow::Error),
+}
+
+#[derive(Template)]
+#[template(path = "app_error.html")]
+struct ErrorFragment<'a> {
+    message: &'a str,
+}
+
+impl AppError {
+    pub fn bad_request(err: impl Display) -> Self {
+        Self::BadRequest(err.to_string())
+    }
+
+    pub fn not_found(err: impl Display) -> Self {
+        Self::NotFound(err.to_string())
+    }
+
+    pub fn conflict(err: impl Display) -> Self {
+        Self::Conflict(err.to_string())
+    }
+
+    /// Prefixes the message while keeping the kind of error
+    pub fn context(self, context: impl Display) -> Self {
+        match self {
+            Self::BadRequest(message) => Self::BadRequest(format!("{}: {}", context, message)),
+            Self::NotFound(message) => Self::NotFound(format!("{}: {}", context, message)),
+            Self::Conflict(message) => Self::Conflict(format!("{}: {}", context, message)),
+            Self::Internal(err) => Self::Internal(err.context(context.to_string())),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Internal errors are unexpected, the rest are the client's doing
+    pub fn log(&self) {
+        match self {
+            Self::Internal(err) => tracing::error!("{:?}", err),
+            _ => tracing::info!("{}", self),
+        }
+    }
+
+    /// Falls back to the bare message if the fragment itself fails to render
+    pub fn render_fragment(&self) -> String {
+        let message = self.to_string();
+        ErrorFragment { message: &message }
+            .render()
+            .unwrap_or(message)
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(message) | Self::NotFound(message) | Self::Conflict(message) => {
+                write!(f, "{}", message)
+            }
+            Self::Internal(err) => write!(f, "Internal error: {:#}", err),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        self.log();
+        (self.status_code(), Html(self.render_fragment())).into_response()
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Internal(err)
+    }
+}
+
+impl From<askama::Error> for AppError {
+    fn from(err: askama::Error) -> Self {
+        Self::Internal(err.into())
+    }
+}
+
+impl From<EnvError> for AppError {
+    fn from(err: EnvError) -> Self {
+        Self::Internal(err.into())
+    }
+}
+
+impl From<EnvHandleError> for AppError {
+    fn from(err: EnvHandleError) -> Self {
+        Self::Internal(err.into())
+    }
+}
+
+impl From<AgentError> for AppError {
+    fn from(err: AgentError) -> Self {
+        Self::Internal(err.into())
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::BadRequest(rejection.body_text())
+    }
+}
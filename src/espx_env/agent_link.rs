@@ -0,0 +1,123 @@
+use anyhow::anyhow;
+use espionox::{
+    agents::memory::Message,
+    environment::{
+        agent_handle::AgentHandle,
+        dispatch::{
+            EnvMessageSender, EnvNotification, EnvRequest, ThreadSafeStreamCompletionHandler,
+        },
+        notification_stack::RefCountedNotificationStack,
+    },
+};
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use super::{ui_listeners::EditAck, EnvironmentState};
+
+/// How long a request may go without its notification showing up
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(10);
+const NOTIFICATION_POLL: Duration = Duration::from_millis(20);
+
+/// Everything it takes to talk to one agent of a running environment, without holding on to the
+/// `AppState`. Taken under a short lock, so agents can reply at the same time while the UI keeps
+/// reading and editing.
+#[derive(Debug, Clone)]
+pub struct AgentLink {
+    pub agent_id: String,
+    handle: AgentHandle,
+    sender: EnvMessageSender,
+    notifications: RefCountedNotificationStack,
+}
+
+impl EnvironmentState {
+    /// Spawns the environment if it isn't running yet. `None` if there's no agent by the id.
+    pub fn agent_link(&mut self, agent_id: &str) -> Result<Option<AgentLink>, anyhow::Error> {
+        if !self.has_handle() {
+            self.spawn()?;
+        }
+        let Some(handle) = self.get_agent_handle(agent_id).cloned() else {
+            return Ok(None);
+        };
+        let env_handle = self.env_handle()?;
+        let notifications = env_handle
+            .notifications
+            .as_ref()
+            .ok_or(anyhow!("Environment handle has no notifications"))?;
+        Ok(Some(AgentLink {
+            agent_id: agent_id.to_owned(),
+            handle,
+            notifications: Arc::clone(notifications),
+            sender: env_handle.new_sender(),
+        }))
+    }
+}
+
+impl AgentLink {
+    /// Pushes the message to the agent's cache and gets the handler streaming its reply
+    #[tracing::instrument(name = "Request stream completion", skip(self, message))]
+    pub async fn stream_completion(
+        &self,
+        message: Message,
+    ) -> Result<ThreadSafeStreamCompletionHandler, anyhow::Error> {
+        let ticket = self.handle.request_stream_completion(message).await?;
+        let noti = self.wait_for_notification(ticket).await?;
+        let stream: &ThreadSafeStreamCompletionHandler = noti
+            .extract_body()
+            .try_into()
+            .map_err(|_| anyhow!("Notification did not carry a stream handler"))?;
+        Ok(Arc::clone(stream))
+    }
+
+    /// Gets the edits queued for the agent made to its cache, returns once the one acknowledged
+    /// is made or turned down. The state update it asks for brings the UI's copy in line.
+    #[tracing::instrument(name = "Apply queued edits", skip(self))]
+    pub async fn apply_edits(&self, ack: EditAck) -> Result<(), anyhow::Error> {
+        if !ack.is_pending() {
+            return Ok(());
+        }
+        self.sync().await?;
+        ack.confirmed().await
+    }
+
+    /// Returns once the UI's copy of the agent's cache is in line with the agent's, with every
+    /// queued edit made
+    pub async fn sync(&self) -> Result<(), anyhow::Error> {
+        let ticket = self.handle.request_state().await?;
+        self.wait_for_notification(ticket).await?;
+        Ok(())
+    }
+
+    /// Like `EnvHandle::wait_for_notification`, which needs the handle borrowed mutably
+    async fn wait_for_notification(&self, ticket: Uuid) -> Result<EnvNotification, anyhow::Error> {
+        tokio::time::timeout(NOTIFICATION_TIMEOUT, async {
+            loop {
+                if let Some(noti) = self.notifications.write().await.take_by_ticket(ticket) {
+                    return noti;
+                }
+                tokio::time::sleep(NOTIFICATION_POLL).await;
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("No notification for ticket {}", ticket))
+    }
+
+    pub async fn push_to_cache(&self, message: Message) -> Result<(), anyhow::Error> {
+        self.sender
+            .lock()
+            .await
+            .send(
+                EnvRequest::PushToCache {
+                    agent_id: self.agent_id.to_owned(),
+                    message,
+                }
+                .into(),
+            )
+            .await
+            .map_err(|_| anyhow!("Environment of agent '{}' is gone", self.agent_id))
+    }
+
+    /// Streamed completions send what they receive back to the environment through this
+    pub fn sender(&self) -> EnvMessageSender {
+        Arc::clone(&self.sender)
+    }
+}
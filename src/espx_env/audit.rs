@@ -0,0 +1,230 @@
+use anyhow::anyhow;
+use espionox::agents::memory::{Message, MessageStack};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{fmt::Display, str::FromStr};
+
+use super::{ui_listeners::StackEdit, EnvironmentState};
+
+/// Entries shown when the filter doesn't ask for a number
+const DEFAULT_LIMIT: u32 = 100;
+
+/// What happened to an agent's memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    EditMessage,
+    RemoveMessage,
+    PushMessage,
+    InsertMessage,
+    MoveMessage,
+    ChangeRole,
+    Truncate,
+    Clear,
+    ReplaceCache,
+    Completion,
+}
+
+impl AuditKind {
+    pub const ALL: [AuditKind; 10] = [
+        AuditKind::EditMessage,
+        AuditKind::RemoveMessage,
+        AuditKind::PushMessage,
+        AuditKind::InsertMessage,
+        AuditKind::MoveMessage,
+        AuditKind::ChangeRole,
+        AuditKind::Truncate,
+        AuditKind::Clear,
+        AuditKind::ReplaceCache,
+        AuditKind::Completion,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::EditMessage => "edit_message",
+            Self::RemoveMessage => "remove_message",
+            Self::PushMessage => "push_message",
+            Self::InsertMessage => "insert_message",
+            Self::MoveMessage => "move_message",
+            Self::ChangeRole => "change_role",
+            Self::Truncate => "truncate",
+            Self::Clear => "clear",
+            Self::ReplaceCache => "replace_cache",
+            Self::Completion => "completion",
+        }
+    }
+}
+
+impl Display for AuditKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuditKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or(anyhow!("Unknown audit kind '{}'", s))
+    }
+}
+
+/// Something done to an agent, `before` and `after` hold the messages it touched
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEvent {
+    pub agent_id: String,
+    pub thread_id: u64,
+    pub kind: AuditKind,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    /// Address of the client that asked for it, `None` if the app did it on its own
+    pub client: Option<String>,
+}
+
+/// An event as it was logged
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    /// UTC, as SQLite's `CURRENT_TIMESTAMP` writes it
+    pub at: String,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+fn describe(message: &Message) -> String {
+    format!("{}: {}", message.role.to_string(), message.content)
+}
+
+impl AuditEvent {
+    /// Call before the edit is made to the cache
+    pub fn edit(
+        agent_id: &str,
+        thread_id: u64,
+        edit: &StackEdit,
+        cache: &MessageStack,
+        client: Option<&str>,
+    ) -> Self {
+        let messages = cache.as_ref();
+        let (kind, before, after) = match edit {
+            StackEdit::EditMessageInCache { idx, new_text } => {
+                let before = messages.get(*idx);
+                (
+                    AuditKind::EditMessage,
+                    before.map(describe),
+                    before.map(|m| format!("{}: {}", m.role.to_string(), new_text)),
+                )
+            }
+            StackEdit::RemoveMessageInCache { idx } => (
+                AuditKind::RemoveMessage,
+                messages.get(*idx).map(describe),
+                None,
+            ),
+            StackEdit::PushMessageToCache { message } => {
+                (AuditKind::PushMessage, None, Some(describe(message)))
+            }
+            StackEdit::InsertAt { message, .. } => {
+                (AuditKind::InsertMessage, None, Some(describe(message)))
+            }
+            StackEdit::Move { from, to } => (
+                AuditKind::MoveMessage,
+                messages
+                    .get(*from)
+                    .map(|m| format!("{} at {}", describe(m), from)),
+                messages
+                    .get(*from)
+                    .map(|m| format!("{} at {}", describe(m), to)),
+            ),
+            StackEdit::ChangeRole { idx, role } => {
+                let before = messages.get(*idx);
+                (
+                    AuditKind::ChangeRole,
+                    before.map(describe),
+                    before.map(|m| format!("{}: {}", role.to_string(), m.content)),
+                )
+            }
+            StackEdit::Truncate { len } => (
+                AuditKind::Truncate,
+                Some(format!("{} messages", messages.len())),
+                Some(format!("{} messages", len)),
+            ),
+            StackEdit::Clear => (
+                AuditKind::Clear,
+                Some(format!("{} messages", messages.len())),
+                Some(String::from("0 messages")),
+            ),
+            StackEdit::ReplaceCache { cache: new_cache } => (
+                AuditKind::ReplaceCache,
+                Some(format!("{} messages", messages.len())),
+                Some(format!("{} messages", new_cache.len())),
+            ),
+        };
+        Self {
+            agent_id: agent_id.to_owned(),
+            thread_id,
+            kind,
+            before,
+            after,
+            client: client.map(str::to_owned),
+        }
+    }
+
+    /// The prompt is the last message of the cache sent to the model
+    pub fn completion(
+        agent_id: &str,
+        thread_id: u64,
+        cache: &MessageStack,
+        client: Option<String>,
+    ) -> Self {
+        Self {
+            agent_id: agent_id.to_owned(),
+            thread_id,
+            kind: AuditKind::Completion,
+            before: None,
+            after: cache.as_ref().last().map(describe),
+            client,
+        }
+    }
+}
+
+/// Narrows the log of one agent down, newest entries first
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    #[serde(default, deserialize_with = "non_empty")]
+    pub kind: Option<AuditKind>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub client: Option<String>,
+    #[serde(default, deserialize_with = "non_empty")]
+    pub limit: Option<u32>,
+}
+
+impl AuditFilter {
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+}
+
+/// Forms send fields left blank as empty strings
+fn non_empty<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(text) if !text.is_empty() => text.parse().map(Some).map_err(serde::de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+impl EnvironmentState {
+    /// `None` if there's no agent by the id, entries of removed agents are kept but not shown
+    pub fn audit_log(
+        &self,
+        agent_id: &str,
+        filter: &AuditFilter,
+    ) -> Result<Option<Vec<AuditEntry>>, anyhow::Error> {
+        if self.ui_handler.get_threads_of_agent(agent_id).is_none() {
+            return Ok(None);
+        }
+        Ok(Some(self.db.load_audit(self.id(), agent_id, filter)?))
+    }
+}
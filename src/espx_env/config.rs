@@ -0,0 +1,443 @@
+use anyhow::{anyhow, bail, Context};
+use espionox::{
+    agents::Agent,
+    language_models::{
+        anthropic::AnthropicCompletionHandler, inference::LLMCompletionHandler,
+        openai::completions::OpenAiCompletionHandler, ModelParameters, ModelProvider, LLM,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::mock::{MockAgent, MockModel};
+
+/// Environment variable holding the path to the agents config file
+pub const CONFIG_ENV_VAR: &str = "BUREAU_CONFIG";
+/// Command line flag holding the path to the agents config file, takes precedence over the env var
+pub const CONFIG_FLAG: &str = "--config";
+/// Used when neither the flag nor the env var are given
+pub const DEFAULT_CONFIG_PATH: &str = "bureau.toml";
+/// Used when the config file doesn't set `database`
+pub const DEFAULT_DATABASE_PATH: &str = "bureau.db";
+/// Id of the environment holding the agents declared at the top level of the config
+pub const DEFAULT_ENV_ID: &str = "default";
+
+/// Environment ids end up in urls, so they can't shadow any of the top level routes
+const RESERVED_ENV_IDS: [&str; 4] = ["ws", "static", "api", "v1"];
+/// Agent ids end up in urls, so they can't shadow any of the environment level routes
+const RESERVED_AGENT_IDS: [&str; 1] = ["agents"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BureauConfig {
+    /// Path of the SQLite database agent caches are persisted to
+    #[serde(default = "default_database_path")]
+    pub database: String,
+    /// Shorthand for an environment named `default` using the usual api key variables,
+    /// moved into `environments` once the config is loaded
+    #[serde(default)]
+    pub agents: Vec<AgentConfig>,
+    #[serde(default)]
+    pub environments: Vec<EnvConfig>,
+    /// Price of each model by its name, usage of models left out is shown without a cost
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPrice>,
+    /// How the agent view streams replies and sends prompts
+    #[serde(default)]
+    pub transport: Transport,
+    /// Addresses of the proxies in front of the app, only their `X-Forwarded-For` is believed
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// `sse` is for networks whose proxies break websocket upgrades
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// htmx-ws, over `/ws`
+    #[default]
+    Ws,
+    /// htmx-sse, streaming from `/<env_id>/<agent_id>/stream` with prompts posted alongside
+    Sse,
+}
+
+/// Dollars per million tokens
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// An espionox environment, with its own api keys and agents
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvConfig {
+    pub id: String,
+    /// Name of the environment variable holding each provider's api key,
+    /// providers left out use their usual variable
+    #[serde(default)]
+    pub api_keys: HashMap<Provider, String>,
+    pub agents: Vec<AgentConfig>,
+}
+
+fn default_database_path() -> String {
+    DEFAULT_DATABASE_PATH.to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    pub id: String,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    pub provider: Provider,
+    pub model: String,
+    #[serde(default)]
+    pub params: AgentParams,
+    /// Replies of a `mock` agent using the `scripted` model, given in turn
+    #[serde(default)]
+    pub responses: Vec<String>,
+    /// Milliseconds a `mock` agent waits before streaming each token, to act like a slow model.
+    /// Keep it under a second, espionox stops waiting for the next token after that.
+    #[serde(default)]
+    pub token_delay_ms: Option<u64>,
+    /// Keeps what's sent to the model within its context window, the whole thread is sent without it
+    #[serde(default)]
+    pub context: Option<ContextConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContextConfig {
+    /// Estimated tokens the messages sent to the model may add up to
+    pub max_tokens: u64,
+    #[serde(default)]
+    pub policy: ContextPolicy,
+}
+
+/// What happens to the messages that don't fit, newest messages are kept first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextPolicy {
+    /// Drops the oldest messages, whatever their role
+    DropOldest,
+    /// Drops the oldest messages, but keeps system and pinned messages
+    #[default]
+    KeepPinned,
+    /// Like `keep_pinned`, with the dropped messages summarized into a system message by a side
+    /// completion of the agent's model
+    Summarize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    OpenAi,
+    Anthropic,
+    /// Answers locally without an api key, see `espx_env::mock`
+    Mock,
+}
+
+/// Mirrors espionox's `ModelParameters`, any field left out falls back to espionox's default
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentParams {
+    /// Between 0 and 200, divided by 100 by espionox
+    pub temperature: Option<u8>,
+    pub frequency_penalty: Option<i8>,
+    pub max_tokens: Option<u32>,
+    pub n: Option<u32>,
+    pub presence_penalty: Option<i8>,
+}
+
+impl Provider {
+    const ALL: [Self; 3] = [Self::OpenAi, Self::Anthropic, Self::Mock];
+
+    /// Variable read when the environment doesn't name another one, `None` if no key is needed
+    pub fn api_key_var(&self) -> Option<&'static str> {
+        match self {
+            Self::OpenAi => Some("OPENAI_API_KEY"),
+            Self::Anthropic => Some("ANTHROPIC_API_KEY"),
+            Self::Mock => None,
+        }
+    }
+
+    /// `None` for the mock provider, which espionox knows nothing about
+    pub fn model_provider(&self) -> Option<ModelProvider> {
+        match self {
+            Self::OpenAi => Some(ModelProvider::OpenAi),
+            Self::Anthropic => Some(ModelProvider::Anthropic),
+            Self::Mock => None,
+        }
+    }
+
+    fn model_names(&self) -> &'static [&'static str] {
+        match self {
+            Self::OpenAi => &["gpt3", "gpt4"],
+            Self::Anthropic => &["opus", "sonnet", "haiku"],
+            Self::Mock => &["echo", "scripted", "recall"],
+        }
+    }
+
+    fn completion_handler(&self, model: &str) -> Option<LLMCompletionHandler> {
+        let handler = match (self, model) {
+            (Self::OpenAi, "gpt3") => OpenAiCompletionHandler::Gpt3.into(),
+            (Self::OpenAi, "gpt4") => OpenAiCompletionHandler::Gpt4.into(),
+            (Self::Anthropic, "opus") => AnthropicCompletionHandler::Opus.into(),
+            (Self::Anthropic, "sonnet") => AnthropicCompletionHandler::Sonnet.into(),
+            (Self::Anthropic, "haiku") => AnthropicCompletionHandler::Haiku.into(),
+            // Never reaches the network, the mock listener answers before espionox handles the request
+            (Self::Mock, "echo" | "scripted" | "recall") => OpenAiCompletionHandler::Gpt3.into(),
+            _ => return None,
+        };
+        Some(handler)
+    }
+}
+
+impl From<&AgentParams> for ModelParameters {
+    fn from(params: &AgentParams) -> Self {
+        let default = ModelParameters::default();
+        ModelParameters {
+            total_token_count: default.total_token_count,
+            temperature: params.temperature.or(default.temperature),
+            frequency_penalty: params.frequency_penalty.or(default.frequency_penalty),
+            max_tokens: params.max_tokens.or(default.max_tokens),
+            n: params.n.or(default.n),
+            presence_penalty: params.presence_penalty.or(default.presence_penalty),
+        }
+    }
+}
+
+/// Ids end up in urls and element ids, so they're kept to a safe set of characters
+fn validate_id(id: &str, reserved: &[&str]) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("id cannot be empty".to_string());
+    }
+    if let Some(c) = id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+    {
+        return Err(format!(
+            "id contains '{}', only ascii letters, digits, '-' and '_' are allowed",
+            c
+        ));
+    }
+    if reserved.contains(&id) {
+        return Err(format!("id '{}' is reserved", id));
+    }
+    Ok(())
+}
+
+impl AgentConfig {
+    /// Checks everything that serde can't, returns a message describing the first problem found
+    pub(super) fn validate(&self) -> Result<(), String> {
+        validate_id(&self.id, &RESERVED_AGENT_IDS)?;
+        if self.provider.completion_handler(&self.model).is_none() {
+            return Err(format!(
+                "unknown model '{}' for provider {:?}, expected one of: {}",
+                self.model,
+                self.provider,
+                self.provider.model_names().join(", ")
+            ));
+        }
+        if let Some(temp) = self.params.temperature {
+            if temp > 200 {
+                return Err(format!(
+                    "temperature must be between 0 and 200, got {}",
+                    temp
+                ));
+            }
+        }
+        let is_scripted = self.provider == Provider::Mock && self.model == "scripted";
+        if is_scripted && self.responses.is_empty() {
+            return Err("the scripted model needs at least one entry in responses".to_string());
+        }
+        if !is_scripted && !self.responses.is_empty() {
+            return Err(
+                "responses can only be given to mock agents using the scripted model".to_string(),
+            );
+        }
+        if self.provider != Provider::Mock && self.token_delay_ms.is_some() {
+            return Err("token_delay_ms can only be given to mock agents".to_string());
+        }
+        if self.context.is_some_and(|c| c.max_tokens == 0) {
+            return Err("context max_tokens must be more than 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// `None` unless the agent uses the mock provider
+    pub fn mock_agent(&self) -> Option<MockAgent> {
+        let model = match (self.provider, self.model.as_str()) {
+            (Provider::Mock, "echo") => MockModel::Echo,
+            (Provider::Mock, "scripted") => MockModel::Scripted(self.responses.clone()),
+            (Provider::Mock, "recall") => MockModel::Recall,
+            _ => return None,
+        };
+        Some(MockAgent {
+            model,
+            token_delay: Duration::from_millis(self.token_delay_ms.unwrap_or_default()),
+        })
+    }
+
+    pub fn llm(&self) -> Result<LLM, anyhow::Error> {
+        let handler = self
+            .provider
+            .completion_handler(&self.model)
+            .ok_or(anyhow!("Unknown model: {}", self.model))?;
+        Ok(LLM::new_completion_model(
+            handler,
+            Some((&self.params).into()),
+        ))
+    }
+
+    pub fn build_agent(&self) -> Result<Agent, anyhow::Error> {
+        Ok(Agent::new(self.system_prompt.as_deref(), self.llm()?))
+    }
+}
+
+impl EnvConfig {
+    pub fn api_key_var(&self, provider: Provider) -> Option<&str> {
+        self.api_keys
+            .get(&provider)
+            .map(String::as_str)
+            .or(provider.api_key_var())
+    }
+
+    pub fn api_key(&self, provider: Provider) -> Result<String, anyhow::Error> {
+        let var = self
+            .api_key_var(provider)
+            .ok_or(anyhow!("{:?} doesn't use an api key", provider))?;
+        std::env::var(var).map_err(|_| anyhow!("{:?} is used, but {} is not set", provider, var))
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        validate_id(&self.id, &RESERVED_ENV_IDS).map_err(|err| anyhow!(err))?;
+        if self.agents.is_empty() {
+            bail!("No agents declared");
+        }
+        let mut seen = HashSet::new();
+        for (i, agent) in self.agents.iter().enumerate() {
+            agent
+                .validate()
+                .map_err(|err| anyhow!("Agent #{} ('{}'): {}", i + 1, agent.id, err))?;
+            if !seen.insert(agent.id.as_str()) {
+                bail!(
+                    "Agent #{} ('{}'): id is used more than once",
+                    i + 1,
+                    agent.id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the api key of every provider used by at least one agent, mock agents don't need one
+    pub fn api_keys(&self) -> Result<HashMap<ModelProvider, String>, anyhow::Error> {
+        let mut map = HashMap::new();
+        for agent in self.agents.iter() {
+            let Some(provider) = agent.provider.model_provider() else {
+                continue;
+            };
+            if map.contains_key(&provider) {
+                continue;
+            }
+            let key = self
+                .api_key(agent.provider)
+                .with_context(|| format!("Agent '{}'", agent.id))?;
+            map.insert(provider, key);
+        }
+        Ok(map)
+    }
+}
+
+impl BureauConfig {
+    /// Loads the config from the path given by `--config`, `BUREAU_CONFIG` or `bureau.toml`, in that order
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let path = config_path(std::env::args(), std::env::var(CONFIG_ENV_VAR).ok())?;
+        tracing::info!("Loading config from {}", path.display());
+        Self::from_path(&path)
+    }
+
+    /// `.json` files are parsed as json, anything else as toml
+    pub fn from_path(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        let config: Self = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid config file {}", path.display()))?,
+            _ => toml::from_str(&contents)
+                .with_context(|| format!("Invalid config file {}", path.display()))?,
+        };
+        let config = config.with_default_env();
+        config
+            .validate()
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Moves top level agents into the `default` environment
+    fn with_default_env(mut self) -> Self {
+        if !self.agents.is_empty() {
+            let env = EnvConfig {
+                id: DEFAULT_ENV_ID.to_string(),
+                api_keys: HashMap::new(),
+                agents: std::mem::take(&mut self.agents),
+            };
+            self.environments.insert(0, env);
+        }
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.environments.is_empty() {
+            bail!("No agents or environments declared");
+        }
+        let mut seen = HashSet::new();
+        for env in self.environments.iter() {
+            env.validate()
+                .with_context(|| format!("Environment '{}'", env.id))?;
+            if !seen.insert(env.id.as_str()) {
+                bail!("Environment '{}': id is used more than once", env.id);
+            }
+        }
+        for (model, price) in self.prices.iter() {
+            if !Provider::ALL
+                .iter()
+                .any(|p| p.model_names().contains(&model.as_str()))
+            {
+                bail!("Price of unknown model '{}'", model);
+            }
+            if price.prompt < 0.0 || price.completion < 0.0 {
+                bail!("Price of model '{}' cannot be negative", model);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn config_path(
+    mut args: impl Iterator<Item = String>,
+    env_var: Option<String>,
+) -> Result<PathBuf, anyhow::Error> {
+    while let Some(arg) = args.next() {
+        if arg == CONFIG_FLAG {
+            return args
+                .next()
+                .map(PathBuf::from)
+                .ok_or(anyhow!("{} expects a path", CONFIG_FLAG));
+        }
+        if let Some(path) = arg.strip_prefix(&format!("{}=", CONFIG_FLAG)) {
+            return Ok(PathBuf::from(path));
+        }
+    }
+    Ok(PathBuf::from(
+        env_var.unwrap_or(DEFAULT_CONFIG_PATH.to_string()),
+    ))
+}
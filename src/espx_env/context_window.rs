@@ -0,0 +1,346 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::anyhow;
+use espionox::{
+    agents::memory::{Message, MessageRole, MessageStack},
+    environment::{
+        dispatch::{
+            listeners::ListenerMethodReturn, Dispatch, EnvListener, EnvMessage, EnvRequest,
+        },
+        ListenerError,
+    },
+    language_models::LLM,
+};
+
+use super::{
+    config::{AgentConfig, ContextConfig, ContextPolicy},
+    mock::MockModel,
+    threads::AgentThreads,
+    usage::{estimate_message_tokens, SentTokens},
+    EnvironmentState,
+};
+
+const SUMMARY_PROMPT: &str = "Summarize the conversation you are given in a few sentences. \
+    Keep every name, fact and decision needed to carry it on.";
+
+/// Which messages of a thread are left out of what's sent to the model
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Window {
+    /// Indices of the messages that don't fit, oldest first
+    pub dropped: BTreeSet<usize>,
+}
+
+impl Window {
+    /// Newest messages are kept first. The last message, the prompt, is always kept, as are system
+    /// and pinned messages unless the policy drops them too.
+    pub fn compute(messages: &[Message], pinned: &BTreeSet<usize>, config: &ContextConfig) -> Self {
+        let always_kept = |idx: usize, m: &Message| {
+            idx + 1 == messages.len()
+                || (config.policy != ContextPolicy::DropOldest
+                    && (m.role == MessageRole::System || pinned.contains(&idx)))
+        };
+        let mut budget = config.max_tokens.saturating_sub(
+            messages
+                .iter()
+                .enumerate()
+                .filter(|(idx, m)| always_kept(*idx, m))
+                .map(|(_, m)| estimate_message_tokens(m))
+                .sum(),
+        );
+
+        let mut dropped = BTreeSet::new();
+        for (idx, m) in messages.iter().enumerate().rev() {
+            if always_kept(idx, m) {
+                continue;
+            }
+            let tokens = estimate_message_tokens(m);
+            // Once a message doesn't fit, neither do the ones before it
+            if dropped.is_empty() && tokens <= budget {
+                budget -= tokens;
+            } else {
+                dropped.insert(idx);
+            }
+        }
+        Self { dropped }
+    }
+
+    /// The summary takes the place of the first dropped message
+    fn apply(&self, cache: &MessageStack, summary: Option<String>) -> MessageStack {
+        let first_dropped = self.dropped.first().copied();
+        let mut summary = summary.map(|s| Message::new_system(&s));
+        let mut windowed = vec![];
+        for (idx, m) in cache.as_ref().iter().enumerate() {
+            if Some(idx) == first_dropped {
+                windowed.extend(summary.take());
+            }
+            if !self.dropped.contains(&idx) {
+                windowed.push(m.clone());
+            }
+        }
+        windowed.into()
+    }
+}
+
+impl EnvironmentState {
+    /// `None` if the agent is sent its whole thread
+    pub fn context_config(&self, agent_id: &str) -> Option<ContextConfig> {
+        self.config
+            .agents
+            .iter()
+            .find(|c| c.id == agent_id)
+            .and_then(|c| c.context)
+    }
+}
+
+/// How summaries of dropped messages are written
+#[derive(Debug, Clone)]
+enum Summarizer {
+    Model(LLM),
+    /// Mock agents summarize locally like they reply, see `MockModel::summarize`
+    Mock,
+}
+
+/// An agent's context window settings
+#[derive(Debug, Clone)]
+pub struct AgentContext {
+    pub config: ContextConfig,
+    summarizer: Summarizer,
+}
+
+impl AgentContext {
+    /// `None` if the agent is sent its whole thread
+    pub fn new(config: &AgentConfig) -> Result<Option<Self>, anyhow::Error> {
+        let Some(context) = config.context else {
+            return Ok(None);
+        };
+        let summarizer = match config.mock_agent() {
+            Some(_) => Summarizer::Mock,
+            None => Summarizer::Model(config.llm()?),
+        };
+        Ok(Some(Self {
+            config: context,
+            summarizer,
+        }))
+    }
+}
+
+/// Whole caches of agents whose completion was requested with only their context window
+type Stash = Arc<Mutex<HashMap<String, MessageStack>>>;
+
+/// Puts the whole cache back before anything else happens to the agent, so edits and replies
+/// land on the whole thread. Inserted before the UI listener.
+#[derive(Debug)]
+pub struct RestoreCacheListener {
+    stash: Stash,
+}
+
+/// Leaves only the context window in the cache of an agent about to be sent to the model.
+/// Inserted after the UI listener, so pending edits are made to the cache first.
+#[derive(Debug)]
+pub struct ContextWindowListener {
+    agents: HashMap<String, AgentContext>,
+    /// The UI's threads, for the messages pinned in each agent's active thread
+    threads: Arc<RwLock<HashMap<String, AgentThreads>>>,
+    stash: Stash,
+    /// Tokens of each window sent, for the usage meter
+    sent_tokens: SentTokens,
+    /// Last summary written for each agent, by a hash of the messages it summarizes
+    summaries: HashMap<String, (u64, String)>,
+}
+
+/// Both listeners share the stash, insert them around the UI listener
+pub fn context_listeners(
+    agents: HashMap<String, AgentContext>,
+    threads: Arc<RwLock<HashMap<String, AgentThreads>>>,
+    sent_tokens: SentTokens,
+) -> (RestoreCacheListener, ContextWindowListener) {
+    let stash = Stash::default();
+    (
+        RestoreCacheListener {
+            stash: Arc::clone(&stash),
+        },
+        ContextWindowListener {
+            agents,
+            threads,
+            stash,
+            sent_tokens,
+            summaries: HashMap::new(),
+        },
+    )
+}
+
+fn message_agent_id(env_message: &EnvMessage) -> Option<&str> {
+    match env_message {
+        EnvMessage::Request(req) => req.agent_id(),
+        EnvMessage::Response(noti) => noti.agent_id(),
+        EnvMessage::Finish => None,
+    }
+}
+
+impl EnvListener for RestoreCacheListener {
+    fn trigger<'l>(&self, env_message: &'l EnvMessage) -> Option<&'l EnvMessage> {
+        let agent_id = message_agent_id(env_message)?;
+        self.stash
+            .lock()
+            .unwrap()
+            .contains_key(agent_id)
+            .then_some(env_message)
+    }
+
+    fn method<'l>(
+        &'l mut self,
+        trigger_message: EnvMessage,
+        dispatch: &'l mut Dispatch,
+    ) -> ListenerMethodReturn<'l> {
+        Box::pin(async move {
+            let agent_id = message_agent_id(&trigger_message).ok_or(ListenerError::NoAgent)?;
+            if let Some(cache) = self.stash.lock().unwrap().remove(agent_id) {
+                let agent = dispatch
+                    .get_agent_mut(agent_id)
+                    .map_err(|_| ListenerError::NoAgent)?;
+                agent.cache = cache;
+            }
+            Ok(trigger_message)
+        })
+    }
+}
+
+impl ContextWindowListener {
+    fn record_sent(&self, agent_id: &str, sent: &MessageStack) {
+        let tokens = sent.as_ref().iter().map(estimate_message_tokens).sum();
+        self.sent_tokens
+            .lock()
+            .unwrap()
+            .insert(agent_id.to_owned(), tokens);
+    }
+
+    /// Reuses the last summary while the dropped messages stay the same
+    async fn summary(
+        &mut self,
+        agent_id: &str,
+        dropped: &[Message],
+        dispatch: &Dispatch,
+    ) -> Result<String, anyhow::Error> {
+        let mut hasher = DefaultHasher::new();
+        for m in dropped {
+            m.role.to_string().hash(&mut hasher);
+            m.content.hash(&mut hasher);
+        }
+        let hash = hasher.finish();
+        if let Some((_, summary)) = self.summaries.get(agent_id).filter(|(h, _)| *h == hash) {
+            return Ok(summary.to_owned());
+        }
+
+        let context = self
+            .agents
+            .get(agent_id)
+            .ok_or(anyhow!("No context settings for agent '{}'", agent_id))?;
+        let summary = match &context.summarizer {
+            Summarizer::Mock => MockModel::summarize(dropped),
+            Summarizer::Model(llm) => {
+                let transcript: Vec<String> = dropped
+                    .iter()
+                    .map(|m| format!("{}: {}", m.role.to_string(), m.content))
+                    .collect();
+                let messages: MessageStack = vec![
+                    Message::new_system(SUMMARY_PROMPT),
+                    Message::new_user(&transcript.join("\n")),
+                ]
+                .into();
+                let api_key = dispatch
+                    .api_key(llm.provider())
+                    .map_err(|err| anyhow!("{:?}", err))?;
+                let summary = llm
+                    .get_io_completion(&messages, api_key, &dispatch.client)
+                    .await
+                    .map_err(|err| anyhow!("{:?}", err))?;
+                format!("Summary of the earlier conversation: {}", summary)
+            }
+        };
+        self.summaries
+            .insert(agent_id.to_owned(), (hash, summary.to_owned()));
+        Ok(summary)
+    }
+}
+
+impl EnvListener for ContextWindowListener {
+    fn trigger<'l>(&self, env_message: &'l EnvMessage) -> Option<&'l EnvMessage> {
+        match env_message {
+            EnvMessage::Request(
+                EnvRequest::GetCompletion { agent_id, .. }
+                | EnvRequest::GetCompletionStreamHandle { agent_id, .. },
+            ) if self.agents.contains_key(agent_id) => Some(env_message),
+            _ => None,
+        }
+    }
+
+    fn method<'l>(
+        &'l mut self,
+        trigger_message: EnvMessage,
+        dispatch: &'l mut Dispatch,
+    ) -> ListenerMethodReturn<'l> {
+        Box::pin(async move {
+            let agent_id = message_agent_id(&trigger_message)
+                .ok_or(ListenerError::NoAgent)?
+                .to_owned();
+            let config = self
+                .agents
+                .get(&agent_id)
+                .ok_or(ListenerError::NoAgent)?
+                .config;
+            let pinned = self
+                .threads
+                .read()
+                .unwrap()
+                .get(&agent_id)
+                .map(|threads| threads.active().pinned.clone())
+                .unwrap_or_default();
+
+            let agent = dispatch
+                .get_agent_ref(&agent_id)
+                .map_err(|_| ListenerError::NoAgent)?;
+            let window = Window::compute(agent.cache.as_ref(), &pinned, &config);
+            if window.dropped.is_empty() {
+                self.record_sent(&agent_id, &agent.cache);
+                return Ok(trigger_message);
+            }
+            tracing::info!(
+                "Leaving {} messages of agent '{}' out of its context window",
+                window.dropped.len(),
+                agent_id
+            );
+
+            let summary = match config.policy {
+                ContextPolicy::Summarize => {
+                    let dropped: Vec<Message> = window
+                        .dropped
+                        .iter()
+                        .map(|idx| agent.cache.as_ref()[*idx].clone())
+                        .collect();
+                    match self.summary(&agent_id, &dropped, dispatch).await {
+                        Ok(summary) => Some(summary),
+                        Err(err) => {
+                            // The completion goes on without the dropped messages
+                            tracing::error!("Could not summarize dropped messages: {:?}", err);
+                            None
+                        }
+                    }
+                }
+                ContextPolicy::DropOldest | ContextPolicy::KeepPinned => None,
+            };
+
+            let agent = dispatch
+                .get_agent_mut(&agent_id)
+                .map_err(|_| ListenerError::NoAgent)?;
+            let windowed = window.apply(&agent.cache, summary);
+            self.record_sent(&agent_id, &windowed);
+            let cache = std::mem::replace(&mut agent.cache, windowed);
+            self.stash.lock().unwrap().insert(agent_id, cache);
+            Ok(trigger_message)
+        })
+    }
+}
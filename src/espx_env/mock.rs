@@ -0,0 +1,159 @@
+use futures::StreamExt;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use espionox::{
+    agents::memory::{Message, MessageRole, MessageStack},
+    environment::{
+        dispatch::{
+            listeners::ListenerMethodReturn, Dispatch, EnvListener, EnvMessage, EnvNotification,
+            EnvRequest,
+        },
+        ListenerError,
+    },
+    language_models::openai::completions::streaming::{
+        CompletionStream, CompletionStreamReceiver, CompletionStreamSender, StreamChoice,
+        StreamDelta, StreamResponse, StreamedCompletionHandler,
+    },
+};
+use tokio::sync::Mutex;
+
+/// How a `mock` agent comes up with its replies, all are deterministic
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockModel {
+    /// Replies with the last user message
+    Echo,
+    /// Replies with the given responses in turn, based on how many assistant messages are in the cache
+    Scripted(Vec<String>),
+    /// Replies with every message it was sent, a `role: content` line each
+    Recall,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockAgent {
+    pub model: MockModel,
+    /// Waited before streaming each token
+    pub token_delay: Duration,
+}
+
+impl MockModel {
+    pub fn reply(&self, cache: &MessageStack) -> String {
+        match self {
+            Self::Echo => cache
+                .as_ref()
+                .iter()
+                .rev()
+                .find(|m| m.role == MessageRole::User)
+                .map(|m| m.content.to_string())
+                .unwrap_or_default(),
+            Self::Scripted(responses) => {
+                let turn = cache
+                    .as_ref()
+                    .iter()
+                    .filter(|m| m.role == MessageRole::Assistant)
+                    .count();
+                responses[turn % responses.len()].to_string()
+            }
+            Self::Recall => cache
+                .as_ref()
+                .iter()
+                .map(|m| format!("{}: {}", m.role.to_string(), m.content))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Stands in for the side completion summarizing messages that fell out of the context window
+    pub fn summarize(messages: &[Message]) -> String {
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        format!("Summary of earlier messages: {}", contents.join(" / "))
+    }
+}
+
+/// Answers completion requests of mock agents in place of the model provider.
+/// The request is replaced with the notification espionox would have sent, so streamed replies
+/// go through the same `StreamedCompletionHandler` as real ones.
+#[derive(Debug)]
+pub struct MockProviderListener {
+    agents: HashMap<String, MockAgent>,
+}
+
+impl MockProviderListener {
+    pub fn new(agents: HashMap<String, MockAgent>) -> Self {
+        Self { agents }
+    }
+
+    /// Streams the reply one word at a time, each with its trailing whitespace
+    fn stream_handler(reply: &str, token_delay: Duration) -> StreamedCompletionHandler {
+        let responses: Vec<_> = reply
+            .split_inclusive(' ')
+            .map(|token| {
+                Ok(StreamResponse {
+                    choices: vec![StreamChoice {
+                        delta: StreamDelta {
+                            role: None,
+                            content: Some(token.to_string()),
+                        },
+                    }],
+                })
+            })
+            .collect();
+        let stream = futures::stream::iter(responses).then(move |response| async move {
+            tokio::time::sleep(token_delay).await;
+            response
+        });
+        let stream: CompletionStream = Box::new(Box::pin(stream));
+        let (tx, rx): (CompletionStreamSender, CompletionStreamReceiver) =
+            tokio::sync::mpsc::channel(50);
+        StreamedCompletionHandler::from((stream, tx, rx))
+    }
+}
+
+impl EnvListener for MockProviderListener {
+    fn trigger<'l>(&self, env_message: &'l EnvMessage) -> Option<&'l EnvMessage> {
+        match env_message {
+            EnvMessage::Request(
+                EnvRequest::GetCompletion { agent_id, .. }
+                | EnvRequest::GetCompletionStreamHandle { agent_id, .. },
+            ) if self.agents.contains_key(agent_id) => Some(env_message),
+            _ => None,
+        }
+    }
+
+    fn method<'l>(
+        &'l mut self,
+        trigger_message: EnvMessage,
+        dispatch: &'l mut Dispatch,
+    ) -> ListenerMethodReturn<'l> {
+        Box::pin(async move {
+            let (ticket, agent_id, stream) = match trigger_message {
+                EnvMessage::Request(EnvRequest::GetCompletion { ticket, agent_id }) => {
+                    (ticket, agent_id, false)
+                }
+                EnvMessage::Request(EnvRequest::GetCompletionStreamHandle { ticket, agent_id }) => {
+                    (ticket, agent_id, true)
+                }
+                _ => return Err(ListenerError::IncorrectTrigger),
+            };
+            let mock = self.agents.get(&agent_id).ok_or(ListenerError::NoAgent)?;
+            let agent = dispatch
+                .get_agent_ref(&agent_id)
+                .map_err(|_| ListenerError::NoAgent)?;
+            let reply = mock.model.reply(&agent.cache);
+            tracing::info!("Mock agent '{}' replying: {}", agent_id, reply);
+
+            let notification = match stream {
+                true => EnvNotification::GotStreamHandle {
+                    ticket,
+                    agent_id,
+                    handler: Arc::new(Mutex::new(Self::stream_handler(&reply, mock.token_delay))),
+                },
+                false => EnvNotification::GotCompletionResponse {
+                    ticket,
+                    agent_id,
+                    message: Message::new_assistant(&reply),
+                },
+            };
+            Ok(notification.into())
+        })
+    }
+}
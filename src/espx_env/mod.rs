@@ -1,76 +1,212 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
+pub mod agent_link;
+pub mod audit;
+pub mod config;
+pub mod context_window;
+pub mod mock;
+pub mod threads;
 pub mod ui_listeners;
+pub mod usage;
 
 use anyhow::anyhow;
-use dotenv::dotenv;
 use espionox::{
-    agents::Agent,
     environment::{agent_handle::AgentHandle, env_handle::EnvHandle, EnvError, Environment},
-    language_models::{ModelProvider, LLM},
+    language_models::ModelProvider,
 };
 
-use self::ui_listeners::UiListenerHandler;
+use crate::{database::Database, websocket::channels::AgentChannels};
+
+use self::{
+    config::{AgentConfig, EnvConfig},
+    context_window::{context_listeners, AgentContext},
+    mock::{MockAgent, MockProviderListener},
+    threads::AgentThreads,
+    ui_listeners::UiListenerHandler,
+    usage::SentTokens,
+};
 
 #[derive(Debug)]
 pub struct EnvironmentState {
     pub env: Environment,
     pub ui_handler: UiListenerHandler,
     agent_handles: HashMap<String, AgentHandle>,
+    /// Agents are kept in sync with the ones currently in the environment
+    config: EnvConfig,
+    api_keys: HashMap<ModelProvider, String>,
     handle: Option<EnvHandle>,
-}
-
-pub fn default_env() -> Environment {
-    dotenv().ok();
-    let api_key = std::env::var("OPENAI_API_KEY").unwrap();
-    let mut map = HashMap::new();
-    tracing::info!("Got openai api key: {}", api_key);
-    map.insert(ModelProvider::OpenAi, api_key);
-    Environment::new(Some("default"), map)
-}
-
-pub fn default_agents() -> Vec<(&'static str, Agent)> {
-    let default_agent = Agent::new(None, LLM::default_openai());
-    let non_default_agent =
-        Agent::new(Some("You are the non default agent"), LLM::default_openai());
-
-    vec![
-        ("default", default_agent),
-        ("non-default", non_default_agent),
-    ]
+    db: Database,
+    sent_tokens: SentTokens,
 }
 
 impl EnvironmentState {
-    pub async fn init() -> Result<Self, anyhow::Error> {
-        let agents = default_agents();
+    /// Agents with threads saved in the database start from them instead of their system prompt.
+    /// Changes to their threads are pushed to the connections subscribed to them on `channels`.
+    pub async fn init(
+        config: &EnvConfig,
+        db: Database,
+        channels: AgentChannels,
+    ) -> Result<Self, anyhow::Error> {
+        let api_keys = config.api_keys()?;
         let mut tup_vec = vec![];
+        for agent_config in config.agents.iter() {
+            let threads = match db.load_threads(&config.id, &agent_config.id)? {
+                Some(threads) => {
+                    tracing::info!("Restored threads of agent '{}'", agent_config.id);
+                    threads
+                }
+                None => {
+                    let threads = AgentThreads::new(
+                        db.first_thread_id(&config.id, &agent_config.id)?,
+                        agent_config.build_agent()?.cache,
+                    );
+                    db.save_thread(&config.id, &agent_config.id, threads.active())?;
+                    db.set_active_thread(&config.id, &agent_config.id, threads.active)?;
+                    threads
+                }
+            };
+            tup_vec.push((agent_config.id.as_str(), threads));
+        }
+
+        let ui_handler = UiListenerHandler::new(&config.id, tup_vec, db.clone(), channels).await;
+
+        let mut state = Self {
+            env: Environment::new(Some(&config.id), api_keys.clone()),
+            ui_handler,
+            handle: None,
+            agent_handles: HashMap::new(),
+            config: config.clone(),
+            api_keys,
+            db,
+            sent_tokens: SentTokens::default(),
+        };
+        state.rebuild_env().await?;
+        Ok(state)
+    }
 
-        for (id, a) in agents.iter() {
-            tup_vec.push((id.to_owned(), a.cache.clone()));
+    /// Replaces the environment with a fresh one holding every configured agent.
+    /// Espionox can't remove agents or add api keys once an environment is built, so this is how
+    /// the set of agents changes at runtime. Agents keep the caches the UI has for them.
+    #[tracing::instrument(name = "Rebuild environment", skip(self))]
+    async fn rebuild_env(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(mut handle) = self.handle.take() {
+            if let Err(err) = self.env.finalize(&mut handle).await {
+                tracing::warn!("Could not finalize environment handle: {:?}", err);
+            }
         }
 
-        let mut ui_handler = UiListenerHandler::new(tup_vec).await;
+        // The agents are rebuilt from the UI's caches, which take the edits they were yet to
+        self.ui_handler.apply_pending_changes();
+        let mut env = Environment::new(Some(&self.env.id), self.api_keys.clone());
         let mut agent_handles = HashMap::new();
+        for agent_config in self.config.agents.iter() {
+            let mut agent = agent_config.build_agent()?;
+            if let Some(cache) = self.ui_handler.get_state_of_agent(&agent_config.id) {
+                agent.cache = cache;
+            }
+            let h = env.insert_agent(Some(&agent_config.id), agent).await?;
+            agent_handles.insert(agent_config.id.to_string(), h);
+        }
+        let mut contexts = HashMap::new();
+        for agent_config in self.config.agents.iter() {
+            if let Some(context) = AgentContext::new(agent_config)? {
+                contexts.insert(agent_config.id.to_string(), context);
+            }
+        }
+        if contexts.is_empty() {
+            self.ui_handler.insert_my_listener(&mut env).await?;
+        } else {
+            let (restore, window) = context_listeners(
+                contexts,
+                self.ui_handler.shared_threads(),
+                Arc::clone(&self.sent_tokens),
+            );
+            env.insert_listener(restore).await?;
+            self.ui_handler.insert_my_listener(&mut env).await?;
+            env.insert_listener(window).await?;
+        }
+        let mock_agents: HashMap<String, MockAgent> = self
+            .config
+            .agents
+            .iter()
+            .filter_map(|c| Some((c.id.to_string(), c.mock_agent()?)))
+            .collect();
+        if !mock_agents.is_empty() {
+            // Inserted after the UI listener so pending edits reach the cache before it's replied to
+            env.insert_listener(MockProviderListener::new(mock_agents))
+                .await?;
+        }
+
+        self.env = env;
+        self.agent_handles = agent_handles;
+        Ok(())
+    }
 
-        let mut env = default_env();
-        for (id, a) in agents.into_iter() {
-            let h = env.insert_agent(Some(id), a).await?;
-            agent_handles.insert(id.to_string(), h);
+    #[tracing::instrument(name = "Insert agent into environment state", skip(self))]
+    pub async fn insert_agent(&mut self, config: AgentConfig) -> Result<(), anyhow::Error> {
+        config
+            .validate()
+            .map_err(|err| anyhow!("Agent '{}': {}", config.id, err))?;
+        if self.agent_handles.contains_key(&config.id) {
+            return Err(anyhow!("Agent '{}' already exists", config.id));
+        }
+        if let Some(provider) = config.provider.model_provider() {
+            if let Entry::Vacant(entry) = self.api_keys.entry(provider) {
+                entry.insert(self.config.api_key(config.provider)?);
+            }
         }
-        ui_handler
-            .insert_my_listener(&mut env)
-            .await
-            .expect("Couldn't insert UI listeners");
 
-        Ok(Self {
-            env,
-            ui_handler,
-            handle: None,
-            agent_handles,
-        })
+        let agent = config.build_agent()?;
+        let first_id = self.db.first_thread_id(self.id(), &config.id)?;
+        self.ui_handler
+            .insert_agent_state(&config.id, AgentThreads::new(first_id, agent.cache));
+        self.config.agents.push(config);
+        self.rebuild_env().await
+    }
+
+    #[tracing::instrument(name = "Remove agent from environment state", skip(self))]
+    pub async fn remove_agent(&mut self, id: &str) -> Result<(), anyhow::Error> {
+        let idx = self
+            .config
+            .agents
+            .iter()
+            .position(|c| c.id == id)
+            .ok_or(anyhow!("No agent with id '{}'", id))?;
+        self.config.agents.remove(idx);
+        self.ui_handler.remove_agent_state(id);
+        self.rebuild_env().await
+    }
+
+    /// Starts a new thread from the agent's system prompt and switches the agent to it
+    #[tracing::instrument(name = "Create thread for agent", skip(self))]
+    pub fn create_thread(&mut self, agent_id: &str, name: &str) -> Result<u64, anyhow::Error> {
+        let config = self
+            .config
+            .agents
+            .iter()
+            .find(|c| c.id == agent_id)
+            .ok_or(anyhow!("No agent with id '{}'", agent_id))?;
+        let cache = config.build_agent()?.cache;
+        self.ui_handler.create_thread(agent_id, name, cache)
     }
 
     pub fn agent_names(&self) -> Vec<String> {
-        self.agent_handles.keys().map(|k| k.to_string()).collect()
+        self.config
+            .agents
+            .iter()
+            .map(|c| c.id.to_string())
+            .collect()
+    }
+
+    /// In the order they were declared or created in
+    pub fn agent_configs(&self) -> &[AgentConfig] {
+        &self.config.agents
+    }
+
+    pub fn id(&self) -> &str {
+        &self.config.id
     }
 
     pub fn env_handle(&mut self) -> Result<&mut EnvHandle, EnvError> {
@@ -84,7 +220,7 @@ impl EnvironmentState {
         let handle = self
             .env
             .spawn_handle()
-            .expect("Why couldn't I spawn handle?");
+            .map_err(|err| anyhow!("Couldn't spawn handle: {}", err))?;
         self.handle = Some(handle);
         Ok(())
     }
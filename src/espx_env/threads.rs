@@ -0,0 +1,206 @@
+use super::ui_listeners::StackEdit;
+use espionox::agents::memory::MessageStack;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+};
+
+/// Name given to the thread every agent starts with
+pub const DEFAULT_THREAD_NAME: &str = "main";
+/// Edits of a thread that can be undone
+const MAX_UNDO: usize = 100;
+
+/// A named conversation, only the active thread's cache is seen by the agent in the environment
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub id: u64,
+    pub name: String,
+    pub cache: MessageStack,
+    /// Kept replies of regenerated messages, by the message's index in the cache
+    pub variants: BTreeMap<usize, Variants>,
+    /// Indices of messages kept in the context window however old they get
+    pub pinned: BTreeSet<usize>,
+    /// Edits made from the UI that can be undone, only kept in memory
+    pub history: EditHistory,
+}
+
+/// Undoes the latest edits to a thread, each recorded as the edit reversing it
+#[derive(Debug, Clone, Default)]
+pub struct EditHistory {
+    undo: Vec<HistoryEdit>,
+    redo: Vec<HistoryEdit>,
+}
+
+/// An edit kept for undo or redo
+#[derive(Debug, Clone)]
+pub struct HistoryEdit {
+    pub edit: StackEdit,
+    /// `message_hash` of the message the edit is aimed at, as the edit before it left it. If that
+    /// message changed since, the edit is refused instead of landing on another one.
+    pub expected: Option<String>,
+}
+
+impl EditHistory {
+    /// A new edit can't be redone over
+    pub fn record(&mut self, inverse: HistoryEdit) {
+        self.push_undo(inverse);
+        self.redo.clear();
+    }
+
+    pub fn pop_undo(&mut self) -> Option<HistoryEdit> {
+        self.undo.pop()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<HistoryEdit> {
+        self.redo.pop()
+    }
+
+    /// Oldest edits are forgotten past `MAX_UNDO`
+    pub fn push_undo(&mut self, inverse: HistoryEdit) {
+        if self.undo.len() == MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.undo.push(inverse);
+    }
+
+    pub fn push_redo(&mut self, inverse: HistoryEdit) {
+        self.redo.push(inverse);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+}
+
+/// Every reply kept for one message, the message's content is the selected one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Variants {
+    pub contents: Vec<String>,
+    pub selected: usize,
+}
+
+impl Variants {
+    pub fn new(content: String) -> Self {
+        Self {
+            contents: vec![content],
+            selected: 0,
+        }
+    }
+
+    /// Adds the content as the last variant and selects it
+    pub fn push(&mut self, content: String) {
+        self.contents.push(content);
+        self.selected = self.contents.len() - 1;
+    }
+}
+
+impl Thread {
+    /// Keeps variants and pins attached to their message, call before the edit is made to the cache
+    pub fn track_edit(&mut self, edit: &StackEdit) {
+        match edit {
+            StackEdit::EditMessageInCache { idx, new_text } => {
+                if let Some(variants) = self.variants.get_mut(idx) {
+                    variants.contents[variants.selected] = new_text.to_string();
+                }
+            }
+            StackEdit::RemoveMessageInCache { idx } => self.reindex(|i| match i.cmp(idx) {
+                Ordering::Less => Some(i),
+                Ordering::Equal => None,
+                Ordering::Greater => Some(i - 1),
+            }),
+            StackEdit::InsertAt { idx, .. } => self.reindex(|i| match i >= *idx {
+                true => Some(i + 1),
+                false => Some(i),
+            }),
+            StackEdit::Move { from, to } => self.reindex(|i| {
+                Some(if i == *from {
+                    *to
+                } else if from < to && (*from..=*to).contains(&i) {
+                    i - 1
+                } else if to < from && (*to..*from).contains(&i) {
+                    i + 1
+                } else {
+                    i
+                })
+            }),
+            StackEdit::Truncate { len } => self.reindex(|i| (i < *len).then_some(i)),
+            StackEdit::PushMessageToCache { .. } | StackEdit::ChangeRole { .. } => {}
+            StackEdit::ReplaceCache { .. } | StackEdit::Clear => {
+                self.variants.clear();
+                self.pinned.clear();
+            }
+        }
+    }
+
+    /// Moves variants and pins to the new index of their message, `None` if it's gone
+    fn reindex(&mut self, new_idx: impl Fn(usize) -> Option<usize>) {
+        self.variants = std::mem::take(&mut self.variants)
+            .into_iter()
+            .filter_map(|(i, variants)| Some((new_idx(i)?, variants)))
+            .collect();
+        self.pinned = std::mem::take(&mut self.pinned)
+            .into_iter()
+            .filter_map(&new_idx)
+            .collect();
+    }
+
+    /// Returns whether the message is pinned now
+    pub fn toggle_pin(&mut self, idx: usize) -> bool {
+        if self.pinned.remove(&idx) {
+            return false;
+        }
+        self.pinned.insert(idx)
+    }
+}
+
+/// Every thread of a single agent, never empty
+#[derive(Debug, Clone)]
+pub struct AgentThreads {
+    pub active: u64,
+    pub threads: Vec<Thread>,
+}
+
+impl AgentThreads {
+    /// `id` is the first thread's, see `Database::first_thread_id`
+    pub fn new(id: u64, cache: MessageStack) -> Self {
+        Self {
+            active: id,
+            threads: vec![Thread {
+                id,
+                name: DEFAULT_THREAD_NAME.to_string(),
+                cache,
+                variants: BTreeMap::new(),
+                pinned: BTreeSet::new(),
+                history: EditHistory::default(),
+            }],
+        }
+    }
+
+    pub fn active(&self) -> &Thread {
+        self.get(self.active)
+            .expect("Active thread should always exist")
+    }
+
+    pub fn active_mut(&mut self) -> &mut Thread {
+        let active = self.active;
+        self.get_mut(active)
+            .expect("Active thread should always exist")
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Thread> {
+        self.threads.iter().find(|t| t.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|t| t.id == id)
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.threads.iter().map(|t| t.id).max().unwrap_or(0) + 1
+    }
+}
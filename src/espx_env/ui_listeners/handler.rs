@@ -1,76 +1,638 @@
-use super::listener::{CacheEdit, UiUpdatesListener};
+use super::listener::{
+    message_hash, CacheEdit, EditError, EditRecord, HistoryAction, QueuedEdit, StackEdit,
+    UiUpdatesListener,
+};
+use crate::{
+    database::Database,
+    espx_env::threads::{AgentThreads, EditHistory, Thread, Variants},
+    websocket::channels::AgentChannels,
+};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
     sync::{Arc, RwLock},
 };
 
-use anyhow::anyhow;
-use espionox::{agents::memory::MessageStack, environment::Environment};
+use anyhow::{anyhow, Context};
+use espionox::{
+    agents::memory::{Message, MessageRole, MessageStack},
+    environment::Environment,
+};
+use tokio::sync::oneshot;
+
+/// A reply taken off the end of a thread to be generated again
+#[derive(Debug)]
+pub struct Regeneration {
+    pub thread_id: u64,
+    /// Where the new reply will end up
+    pub reply_idx: usize,
+    /// Prompt the taken reply answered
+    pub prompt: Message,
+    /// Put back if no new reply takes its place
+    reply: Message,
+    /// Variants the taken reply had
+    previous: Option<Variants>,
+    keep_variants: bool,
+}
+
+/// Resolves once the agent's cache took an edit or turned it down. Edits to a thread that isn't
+/// active are only made to the UI's copy, so there's nothing to wait for.
+#[derive(Debug, Default)]
+pub struct EditAck(Option<oneshot::Receiver<Result<(), EditError>>>);
+
+impl EditAck {
+    /// Whether the edit still has to be made to the agent
+    pub fn is_pending(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Only resolves once something gets the listener to make the queued edits, like a request
+    /// for the agent's state
+    pub async fn confirmed(self) -> Result<(), anyhow::Error> {
+        let Some(receiver) = self.0 else {
+            return Ok(());
+        };
+        match receiver.await {
+            Ok(result) => Ok(result?),
+            // The agent was removed, along with the edits queued for it
+            Err(_) => Ok(()),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct UiListenerHandler {
-    cache_states: Arc<RwLock<HashMap<String, MessageStack>>>,
-    cache_changes: Arc<RwLock<VecDeque<CacheEdit>>>,
-    listener: Option<UiUpdatesListener>,
+    cache_states: Arc<RwLock<HashMap<String, AgentThreads>>>,
+    cache_changes: Arc<RwLock<VecDeque<QueuedEdit>>>,
+    /// Client asking for each agent's next completion, for the audit log
+    completion_clients: Arc<RwLock<HashMap<String, String>>>,
+    /// Id of the environment the agents belong to, threads are saved under it
+    env_id: String,
+    db: Database,
+    /// Where changes to the agents' active threads are pushed to their subscribers
+    channels: AgentChannels,
 }
 
 impl UiListenerHandler {
-    pub async fn new(agent_tup_vec: Vec<(&str, MessageStack)>) -> Self {
+    pub async fn new(
+        env_id: &str,
+        agent_tup_vec: Vec<(&str, AgentThreads)>,
+        db: Database,
+        channels: AgentChannels,
+    ) -> Self {
         let mut states = HashMap::new();
-        for (id, cache) in agent_tup_vec {
-            states.insert(id.to_owned(), cache);
+        for (id, threads) in agent_tup_vec {
+            states.insert(id.to_owned(), threads);
         }
         let cache_states = Arc::new(RwLock::new(states));
         let cache_changes = Arc::new(RwLock::new(VecDeque::new()));
-        let listener = Some(UiUpdatesListener::new(
-            Arc::clone(&cache_changes),
-            Arc::clone(&cache_states),
-        ));
 
         Self {
             cache_states,
             cache_changes,
-            listener,
+            completion_clients: Arc::new(RwLock::new(HashMap::new())),
+            env_id: env_id.to_owned(),
+            db,
+            channels,
+        }
+    }
+
+    pub fn insert_agent_state(&mut self, id: &str, threads: AgentThreads) {
+        self.save_threads(id, &threads);
+        self.cache_states
+            .write()
+            .unwrap()
+            .insert(id.to_owned(), threads);
+    }
+
+    /// Removes the agent's threads along with any of its changes that haven't been applied
+    pub fn remove_agent_state(&mut self, id: &str) {
+        self.cache_states.write().unwrap().remove(id);
+        if let Err(err) = self.db.delete_agent(&self.env_id, id) {
+            tracing::error!(
+                "Could not delete saved threads of agent '{}': {:?}",
+                id,
+                err
+            );
+        }
+        self.cache_changes
+            .write()
+            .unwrap()
+            .retain(|queued| queued.change.agent_id != id);
+    }
+
+    /// Makes the edits the agents haven't taken yet to the UI's copies instead, before the
+    /// environment is rebuilt from them, and tells whoever queued them
+    pub fn apply_pending_changes(&mut self) {
+        let queued: Vec<QueuedEdit> = self.cache_changes.write().unwrap().drain(..).collect();
+        let mut states = self.cache_states.write().unwrap();
+        for QueuedEdit {
+            change,
+            ack,
+            record,
+        } in queued
+        {
+            let thread = states
+                .get_mut(&change.agent_id)
+                .and_then(|threads| threads.get_mut(change.thread_id));
+            let result = match (thread, record) {
+                (Some(thread), Some(record)) => change.check(&thread.cache).and_then(|_| {
+                    record.commit(
+                        &self.db,
+                        &self.env_id,
+                        &change.agent_id,
+                        thread,
+                        change.edit,
+                    )
+                }),
+                // Thread switches, the UI's copy is switched already
+                _ => Ok(()),
+            };
+            if let Some(ack) = ack {
+                let _ = ack.send(result);
+            }
         }
     }
 
+    /// Returns the cache of the agent's active thread
     pub fn get_state_of_agent(&self, id: &str) -> Option<MessageStack> {
+        let states = self.cache_states.read().unwrap();
+        states.get(id).map(|threads| threads.active().cache.clone())
+    }
+
+    pub fn get_threads_of_agent(&self, id: &str) -> Option<AgentThreads> {
         let states = self.cache_states.read().unwrap();
         states.get(id).cloned()
     }
 
-    /// Pushes to changes and pre-emptively updates cache state
-    #[tracing::instrument(name = "Push change and update cache state", skip(self))]
-    pub fn push_to_changes(&mut self, edit: CacheEdit) -> Result<(), anyhow::Error> {
-        tracing::info!("getting states write lock");
-        let mut states = match self.cache_states.write() {
-            Ok(s) => Some(s),
-            Err(err) => {
-                tracing::error!("ERROR GETTING WRITE LOCK: {:?}", err);
-                None
+    pub fn get_thread(&self, agent_id: &str, thread_id: u64) -> Option<Thread> {
+        let states = self.cache_states.read().unwrap();
+        states.get(agent_id)?.get(thread_id).cloned()
+    }
+
+    /// Queues the edit for the agent, it can be undone once the agent took it
+    pub fn push_to_changes(
+        &mut self,
+        edit: CacheEdit,
+        client: Option<&str>,
+    ) -> Result<EditAck, anyhow::Error> {
+        self.apply_edit(edit, client, HistoryAction::Record)
+    }
+
+    /// Reverses the thread's latest edit, `None` if there's nothing to undo
+    #[tracing::instrument(name = "Undo edit", skip(self))]
+    pub fn undo(
+        &mut self,
+        agent_id: &str,
+        thread_id: u64,
+        client: Option<&str>,
+    ) -> Result<Option<EditAck>, anyhow::Error> {
+        let Some(edit) = self.with_history(agent_id, thread_id, EditHistory::pop_undo)? else {
+            return Ok(None);
+        };
+        let ack = self
+            .apply_edit(
+                CacheEdit {
+                    agent_id: agent_id.to_string(),
+                    thread_id,
+                    edit: edit.edit,
+                    expected: edit.expected,
+                },
+                client,
+                HistoryAction::Undo,
+            )
+            .context("Edit can no longer be undone")?;
+        Ok(Some(ack))
+    }
+
+    /// Makes the thread's latest undone edit again, `None` if there's nothing to redo
+    #[tracing::instrument(name = "Redo edit", skip(self))]
+    pub fn redo(
+        &mut self,
+        agent_id: &str,
+        thread_id: u64,
+        client: Option<&str>,
+    ) -> Result<Option<EditAck>, anyhow::Error> {
+        let Some(edit) = self.with_history(agent_id, thread_id, EditHistory::pop_redo)? else {
+            return Ok(None);
+        };
+        let ack = self
+            .apply_edit(
+                CacheEdit {
+                    agent_id: agent_id.to_string(),
+                    thread_id,
+                    edit: edit.edit,
+                    expected: edit.expected,
+                },
+                client,
+                HistoryAction::Redo,
+            )
+            .context("Edit can no longer be redone")?;
+        Ok(Some(ack))
+    }
+
+    fn with_history<T>(
+        &self,
+        agent_id: &str,
+        thread_id: u64,
+        f: impl FnOnce(&mut EditHistory) -> T,
+    ) -> Result<T, anyhow::Error> {
+        let mut states = self.cache_states.write().unwrap();
+        let thread = states
+            .get_mut(agent_id)
+            .ok_or(anyhow!("No agent with id '{}'", agent_id))?
+            .get_mut(thread_id)
+            .ok_or(anyhow!("No thread with id {}", thread_id))?;
+        Ok(f(&mut thread.history))
+    }
+
+    /// Queues the edit for the agent, it's made to the UI's copy and kept once the agent took it.
+    /// Edits to a thread that isn't active are only made to the copy, the agent doesn't see them
+    /// until it's switched to.
+    #[tracing::instrument(name = "Queue change", skip(self))]
+    fn apply_edit(
+        &mut self,
+        edit: CacheEdit,
+        client: Option<&str>,
+        history: HistoryAction,
+    ) -> Result<EditAck, anyhow::Error> {
+        let mut states = self.cache_states.write().unwrap();
+        let threads = states
+            .get_mut(&edit.agent_id)
+            .ok_or(anyhow!("No agent by edit's given id"))?;
+        let is_active = threads.active == edit.thread_id;
+        let thread = threads
+            .get_mut(edit.thread_id)
+            .ok_or(anyhow!("No thread by edit's given id"))?;
+
+        edit.check(&thread.cache)?;
+        let record = EditRecord {
+            client: client.map(str::to_owned),
+            history,
+        };
+        if !is_active {
+            record.commit(&self.db, &self.env_id, &edit.agent_id, thread, edit.edit)?;
+            return Ok(EditAck::default());
+        }
+        let (ack, receiver) = oneshot::channel();
+        self.cache_changes.write().unwrap().push_back(QueuedEdit {
+            change: edit,
+            ack: Some(ack),
+            record: Some(record),
+        });
+        Ok(EditAck(Some(receiver)))
+    }
+
+    /// Queues removal of the active thread's last reply along with the prompt before it, so the
+    /// prompt can be sent again. Unless `keep_variants`, the reply and its variants are dropped.
+    #[tracing::instrument(name = "Take last reply", skip(self))]
+    pub fn take_last_reply(
+        &mut self,
+        agent_id: &str,
+        keep_variants: bool,
+        client: Option<&str>,
+    ) -> Result<(Regeneration, EditAck), anyhow::Error> {
+        let regeneration = {
+            let states = self.cache_states.read().unwrap();
+            let thread = states
+                .get(agent_id)
+                .ok_or(anyhow!("No agent with id '{}'", agent_id))?
+                .active();
+            let messages = thread.cache.as_ref();
+            let (prompt, reply) = match messages.as_slice() {
+                [.., prompt, reply]
+                    if prompt.role == MessageRole::User && reply.role == MessageRole::Assistant =>
+                {
+                    (prompt, reply)
+                }
+                _ => return Err(anyhow!("Last message is not a reply to a prompt")),
+            };
+            let reply_idx = messages.len() - 1;
+            Regeneration {
+                thread_id: thread.id,
+                reply_idx,
+                prompt: prompt.clone(),
+                reply: reply.clone(),
+                previous: thread.variants.get(&reply_idx).cloned(),
+                keep_variants,
+            }
+        };
+        let mut ack = EditAck::default();
+        for idx in [regeneration.reply_idx, regeneration.reply_idx - 1] {
+            ack = self.apply_edit(
+                CacheEdit {
+                    agent_id: agent_id.to_string(),
+                    thread_id: regeneration.thread_id,
+                    edit: StackEdit::RemoveMessageInCache { idx },
+                    expected: None,
+                },
+                client,
+                HistoryAction::Skip,
+            )?;
+        }
+        Ok((regeneration, ack))
+    }
+
+    /// Keeps the regenerated reply as the selected variant, if earlier variants were kept. An
+    /// empty reply, cancelled before its first token, isn't one, restore the taken reply instead.
+    pub fn finish_regeneration(&mut self, agent_id: &str, regeneration: Regeneration, reply: &str) {
+        if !regeneration.keep_variants || reply.is_empty() {
+            return;
+        }
+        let mut variants = regeneration
+            .previous
+            .unwrap_or_else(|| Variants::new(regeneration.reply.content.to_string()));
+        variants.push(reply.to_string());
+        self.set_variants(
+            agent_id,
+            regeneration.thread_id,
+            regeneration.reply_idx,
+            variants,
+        );
+    }
+
+    /// Puts back what `take_last_reply` took when no new reply replaced it. The prompt is only
+    /// pushed if it's not at the end of the thread already, it's sent again before the reply
+    /// fails. Returns the ack of the last edit, the agent makes them in order.
+    #[tracing::instrument(name = "Restore last reply", skip(self))]
+    pub fn restore_reply(
+        &mut self,
+        agent_id: &str,
+        regeneration: Regeneration,
+        client: Option<&str>,
+    ) -> Result<EditAck, anyhow::Error> {
+        let thread = self
+            .get_thread(agent_id, regeneration.thread_id)
+            .ok_or(anyhow!("No thread with id {}", regeneration.thread_id))?;
+        let prompt_is_back = thread.cache.as_ref().last().map(message_hash)
+            == Some(message_hash(&regeneration.prompt));
+        let reply_idx = thread.cache.len() + usize::from(!prompt_is_back);
+        let mut messages = vec![regeneration.reply];
+        if !prompt_is_back {
+            messages.insert(0, regeneration.prompt);
+        }
+        let mut ack = EditAck::default();
+        for message in messages {
+            ack = self.apply_edit(
+                CacheEdit {
+                    agent_id: agent_id.to_string(),
+                    thread_id: regeneration.thread_id,
+                    edit: StackEdit::PushMessageToCache { message },
+                    expected: None,
+                },
+                client,
+                HistoryAction::Skip,
+            )?;
+        }
+        if let Some(variants) = regeneration.previous {
+            self.set_variants(agent_id, regeneration.thread_id, reply_idx, variants);
+        }
+        Ok(ack)
+    }
+
+    fn set_variants(&mut self, agent_id: &str, thread_id: u64, idx: usize, variants: Variants) {
+        let mut states = self.cache_states.write().unwrap();
+        let Some(thread) = states
+            .get_mut(agent_id)
+            .and_then(|threads| threads.get_mut(thread_id))
+        else {
+            return;
+        };
+        thread.variants.insert(idx, variants);
+        if let Err(err) = self.db.save_thread(&self.env_id, agent_id, thread) {
+            tracing::error!("Could not save variants: {:?}", err);
+        }
+    }
+
+    /// Swaps the message's content for another of its variants
+    #[tracing::instrument(name = "Select variant", skip(self))]
+    pub fn select_variant(
+        &mut self,
+        agent_id: &str,
+        thread_id: u64,
+        idx: usize,
+        variant: usize,
+        client: Option<&str>,
+    ) -> Result<EditAck, anyhow::Error> {
+        let new_text = {
+            let mut states = self.cache_states.write().unwrap();
+            let variants = states
+                .get_mut(agent_id)
+                .ok_or(anyhow!("No agent with id '{}'", agent_id))?
+                .get_mut(thread_id)
+                .ok_or(anyhow!("No thread with id {}", thread_id))?
+                .variants
+                .get_mut(&idx)
+                .ok_or(EditError::NoVariant { idx, variant: None })?;
+            let content = variants
+                .contents
+                .get(variant)
+                .ok_or(EditError::NoVariant {
+                    idx,
+                    variant: Some(variant),
+                })?
+                .clone();
+            variants.selected = variant;
+            content
+        };
+        let ack = self.apply_edit(
+            CacheEdit {
+                agent_id: agent_id.to_string(),
+                thread_id,
+                edit: StackEdit::EditMessageInCache { idx, new_text },
+                expected: None,
+            },
+            client,
+            HistoryAction::Skip,
+        )?;
+        Ok(ack)
+    }
+
+    /// Pins or unpins the message, returns whether it's pinned now
+    #[tracing::instrument(name = "Toggle pin", skip(self))]
+    pub fn toggle_pin(
+        &mut self,
+        agent_id: &str,
+        thread_id: u64,
+        idx: usize,
+    ) -> Result<bool, anyhow::Error> {
+        let mut states = self.cache_states.write().unwrap();
+        let threads = states
+            .get_mut(agent_id)
+            .ok_or(anyhow!("No agent with id '{}'", agent_id))?;
+        let is_active = threads.active == thread_id;
+        let thread = threads
+            .get_mut(thread_id)
+            .ok_or(anyhow!("No thread with id {}", thread_id))?;
+        if idx >= thread.cache.len() {
+            return Err(EditError::OutOfBounds {
+                idx,
+                len: thread.cache.len(),
             }
+            .into());
         }
-        .unwrap();
+        let pinned = thread.toggle_pin(idx);
+        if let Err(err) = self.db.save_thread(&self.env_id, agent_id, thread) {
+            tracing::error!("Could not save pins: {:?}", err);
+        }
+        // Pins don't touch the agent's cache, so no state update tells the subscribers
+        if is_active {
+            self.channels
+                .history_changed(&self.env_id, agent_id, thread_id);
+        }
+        Ok(pinned)
+    }
 
-        let mut agent_mem = states
-            .remove(&edit.agent_id)
-            .ok_or(anyhow!("No agent by edit's given id"))?;
-        tracing::warn!("Removed agent memory from states");
+    /// Adds an empty thread to the agent and makes it the active one, returns the new thread's id
+    #[tracing::instrument(name = "Create thread", skip(self, cache))]
+    pub fn create_thread(
+        &mut self,
+        agent_id: &str,
+        name: &str,
+        cache: MessageStack,
+    ) -> Result<u64, anyhow::Error> {
+        let id = {
+            let mut states = self.cache_states.write().unwrap();
+            let threads = states
+                .get_mut(agent_id)
+                .ok_or(anyhow!("No agent with id '{}'", agent_id))?;
+            let id = self
+                .db
+                .next_thread_id(&self.env_id, agent_id, threads.next_id())
+                .context("Could not take a thread id")?;
+            let thread = Thread {
+                id,
+                name: name.to_string(),
+                cache,
+                variants: BTreeMap::new(),
+                pinned: BTreeSet::new(),
+                history: EditHistory::default(),
+            };
+            if let Err(err) = self.db.save_thread(&self.env_id, agent_id, &thread) {
+                tracing::error!("Could not save new thread: {:?}", err);
+            }
+            threads.threads.push(thread);
+            id
+        };
+        self.switch_thread(agent_id, id)?;
+        Ok(id)
+    }
 
-        let edit_clone = edit.clone();
+    /// Makes the thread active and queues its cache to replace the agent's
+    #[tracing::instrument(name = "Switch thread", skip(self))]
+    pub fn switch_thread(&mut self, agent_id: &str, thread_id: u64) -> Result<(), anyhow::Error> {
+        let mut states = self.cache_states.write().unwrap();
+        let threads = states
+            .get_mut(agent_id)
+            .ok_or(anyhow!("No agent with id '{}'", agent_id))?;
+        let cache = threads
+            .get(thread_id)
+            .ok_or(anyhow!("No thread with id {}", thread_id))?
+            .cache
+            .clone();
+        threads.active = thread_id;
 
-        edit_clone.edit.make_edit(&mut agent_mem);
+        if let Err(err) = self.db.set_active_thread(&self.env_id, agent_id, thread_id) {
+            tracing::error!("Could not save active thread: {:?}", err);
+        }
+        self.cache_changes.write().unwrap().push_back(QueuedEdit {
+            change: CacheEdit {
+                agent_id: agent_id.to_string(),
+                thread_id,
+                edit: StackEdit::ReplaceCache { cache },
+                expected: None,
+            },
+            ack: None,
+            record: None,
+        });
+        Ok(())
+    }
 
-        tracing::info!("Edit to agent memory has been made, re-inserting");
-        states.insert(edit_clone.agent_id, agent_mem.clone());
+    pub fn rename_thread(
+        &mut self,
+        agent_id: &str,
+        thread_id: u64,
+        name: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut states = self.cache_states.write().unwrap();
+        let thread = states
+            .get_mut(agent_id)
+            .ok_or(anyhow!("No agent with id '{}'", agent_id))?
+            .get_mut(thread_id)
+            .ok_or(anyhow!("No thread with id {}", thread_id))?;
+        thread.name = name.to_string();
+        if let Err(err) = self.db.save_thread(&self.env_id, agent_id, thread) {
+            tracing::error!("Could not save renamed thread: {:?}", err);
+        }
+        Ok(())
+    }
 
-        self.cache_changes.write().unwrap().push_back(edit);
+    /// Deleting the active thread switches the agent to its most recent remaining thread
+    #[tracing::instrument(name = "Delete thread", skip(self))]
+    pub fn delete_thread(&mut self, agent_id: &str, thread_id: u64) -> Result<(), anyhow::Error> {
+        let switch_to = {
+            let mut states = self.cache_states.write().unwrap();
+            let threads = states
+                .get_mut(agent_id)
+                .ok_or(anyhow!("No agent with id '{}'", agent_id))?;
+            if threads.get(thread_id).is_none() {
+                return Err(anyhow!("No thread with id {}", thread_id));
+            }
+            if threads.threads.len() == 1 {
+                return Err(anyhow!("Cannot delete an agent's only thread"));
+            }
+            threads.threads.retain(|t| t.id != thread_id);
+            if let Err(err) = self.db.delete_thread(&self.env_id, agent_id, thread_id) {
+                tracing::error!("Could not delete saved thread: {:?}", err);
+            }
+            match threads.active == thread_id {
+                true => threads.threads.last().map(|t| t.id),
+                false => None,
+            }
+        };
+        if let Some(id) = switch_to {
+            self.switch_thread(agent_id, id)?;
+        }
         Ok(())
     }
 
+    fn save_threads(&self, agent_id: &str, threads: &AgentThreads) {
+        for thread in threads.threads.iter() {
+            if let Err(err) = self.db.save_thread(&self.env_id, agent_id, thread) {
+                tracing::error!("Could not save thread: {:?}", err);
+            }
+        }
+        if let Err(err) = self
+            .db
+            .set_active_thread(&self.env_id, agent_id, threads.active)
+        {
+            tracing::error!("Could not save active thread: {:?}", err);
+        }
+    }
+
+    /// The completion the agent is asked for next gets logged under the client
+    pub fn expect_completion(&self, agent_id: &str, client: &str) {
+        self.completion_clients
+            .write()
+            .unwrap()
+            .insert(agent_id.to_owned(), client.to_owned());
+    }
+
+    /// The threads of every agent, as the listeners see them
+    pub fn shared_threads(&self) -> Arc<RwLock<HashMap<String, AgentThreads>>> {
+        Arc::clone(&self.cache_states)
+    }
+
+    /// Inserts a listener sharing this handler's states and changes, can be called once per environment
     pub async fn insert_my_listener(&mut self, env: &mut Environment) -> Result<(), anyhow::Error> {
-        env.insert_listener(self.listener.take().ok_or(anyhow!("No edit listener!!"))?)
-            .await?;
+        let listener = UiUpdatesListener::new(
+            Arc::clone(&self.cache_changes),
+            Arc::clone(&self.cache_states),
+            Arc::clone(&self.completion_clients),
+            &self.env_id,
+            self.db.clone(),
+            self.channels.clone(),
+        );
+        env.insert_listener(listener).await?;
         Ok(())
     }
 }
@@ -1,128 +1,499 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fmt::{Display, Formatter},
+    hash::{Hash, Hasher},
     sync::{Arc, RwLock},
 };
 
+use crate::{
+    database::Database,
+    espx_env::{
+        audit::AuditEvent,
+        threads::{AgentThreads, HistoryEdit, Thread},
+    },
+    websocket::channels::AgentChannels,
+};
 use espionox::{
-    agents::memory::{Message, MessageStack},
-    environment::dispatch::{EnvListener, EnvMessage, EnvNotification, EnvRequest},
+    agents::memory::{Message, MessageRole, MessageStack},
+    environment::{
+        dispatch::{
+            listeners::ListenerMethodReturn, Dispatch, EnvListener, EnvMessage, EnvNotification,
+            EnvRequest,
+        },
+        ListenerError,
+    },
 };
+use tokio::sync::oneshot;
 
 #[derive(Debug, Clone)]
 pub enum StackEdit {
-    EditMessageInCache { idx: usize, new_text: String },
-    RemoveMessageInCache { idx: usize },
-    PushMessageToCache { message: Message },
+    EditMessageInCache {
+        idx: usize,
+        new_text: String,
+    },
+    RemoveMessageInCache {
+        idx: usize,
+    },
+    PushMessageToCache {
+        message: Message,
+    },
+    /// Puts the message before the one at `idx`, or at the end if `idx` is the cache's length
+    InsertAt {
+        idx: usize,
+        message: Message,
+    },
+    /// Takes the message out and puts it back so it ends up at `to`
+    Move {
+        from: usize,
+        to: usize,
+    },
+    ChangeRole {
+        idx: usize,
+        role: MessageRole,
+    },
+    /// Keeps the first `len` messages
+    Truncate {
+        len: usize,
+    },
+    Clear,
+    /// Swaps the whole cache out, used when the agent's active thread changes
+    ReplaceCache {
+        cache: MessageStack,
+    },
 }
 
 impl StackEdit {
+    /// Index of the message the edit is aimed at, whose hash the client may send along
+    pub fn target(&self) -> Option<usize> {
+        match self {
+            Self::EditMessageInCache { idx, .. }
+            | Self::RemoveMessageInCache { idx }
+            | Self::InsertAt { idx, .. }
+            | Self::ChangeRole { idx, .. } => Some(*idx),
+            Self::Move { from, .. } => Some(*from),
+            Self::Truncate { len } => len.checked_sub(1),
+            Self::PushMessageToCache { .. } | Self::ReplaceCache { .. } | Self::Clear => None,
+        }
+    }
+
+    /// Errors if an index the edit uses is out of the cache's bounds
+    pub fn check_bounds(&self, cache: &MessageStack) -> Result<(), EditError> {
+        let len = cache.len();
+        let out_of_bounds = match self {
+            Self::EditMessageInCache { idx, .. }
+            | Self::RemoveMessageInCache { idx }
+            | Self::ChangeRole { idx, .. } => (*idx >= len).then_some(*idx),
+            Self::InsertAt { idx, .. } => (*idx > len).then_some(*idx),
+            Self::Move { from, to } => [*from, *to].into_iter().find(|idx| *idx >= len),
+            Self::Truncate { len: new_len } => (*new_len > len).then_some(*new_len),
+            Self::PushMessageToCache { .. } | Self::ReplaceCache { .. } | Self::Clear => None,
+        };
+        match out_of_bounds {
+            Some(idx) => Err(EditError::OutOfBounds { idx, len }),
+            None => Ok(()),
+        }
+    }
+
+    /// The edit undoing this one once it's made to the cache, call once its bounds are checked
+    pub fn inverse(&self, cache: &MessageStack) -> Self {
+        let messages = cache.as_ref();
+        match self {
+            Self::EditMessageInCache { idx, .. } => Self::EditMessageInCache {
+                idx: *idx,
+                new_text: messages[*idx].content.to_string(),
+            },
+            Self::RemoveMessageInCache { idx } => Self::InsertAt {
+                idx: *idx,
+                message: messages[*idx].clone(),
+            },
+            Self::PushMessageToCache { .. } => Self::RemoveMessageInCache {
+                idx: messages.len(),
+            },
+            Self::InsertAt { idx, .. } => Self::RemoveMessageInCache { idx: *idx },
+            Self::Move { from, to } => Self::Move {
+                from: *to,
+                to: *from,
+            },
+            Self::ChangeRole { idx, .. } => Self::ChangeRole {
+                idx: *idx,
+                role: messages[*idx].role.clone(),
+            },
+            Self::Truncate { .. } | Self::ReplaceCache { .. } | Self::Clear => Self::ReplaceCache {
+                cache: cache.clone(),
+            },
+        }
+    }
+
     #[tracing::instrument(name = "Make edit to messagestack")]
-    pub(super) fn make_edit(self, cache: &mut MessageStack) {
+    pub(super) fn make_edit(self, cache: &mut MessageStack) -> Result<(), EditError> {
+        self.check_bounds(cache)?;
         match self {
             Self::PushMessageToCache { message } => {
                 cache.push(message);
             }
             Self::EditMessageInCache { idx, new_text } => {
-                if let Some(m) = cache.as_mut().iter_mut().nth(idx) {
+                if let Some(m) = cache.as_mut().get_mut(idx) {
                     m.content = new_text.to_string();
                 }
             }
             Self::RemoveMessageInCache { idx } => {
                 cache.as_mut().remove(idx);
             }
+            Self::InsertAt { idx, message } => {
+                cache.as_mut().insert(idx, message);
+            }
+            Self::Move { from, to } => {
+                let message = cache.as_mut().remove(from);
+                cache.as_mut().insert(to, message);
+            }
+            Self::ChangeRole { idx, role } => {
+                if let Some(m) = cache.as_mut().get_mut(idx) {
+                    m.role = role;
+                }
+            }
+            Self::Truncate { len } => {
+                cache.as_mut().truncate(len);
+            }
+            Self::Clear => {
+                cache.as_mut().clear();
+            }
+            Self::ReplaceCache { cache: new_cache } => {
+                *cache = new_cache;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Short hash of a message's role and content. The history hands it out with each message, so
+/// edits can tell whether their message is still where the client saw it.
+pub fn message_hash(message: &Message) -> String {
+    let mut hasher = DefaultHasher::new();
+    message.role.to_string().hash(&mut hasher);
+    message.content.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Why an edit couldn't be made to a cache
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditError {
+    OutOfBounds {
+        idx: usize,
+        len: usize,
+    },
+    /// The message at the edit's target isn't the one the client saw anymore
+    Conflict {
+        idx: usize,
+    },
+    /// The message has no variants, or not as many as the one asked for
+    NoVariant {
+        idx: usize,
+        variant: Option<usize>,
+    },
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfBounds { idx, len } => {
+                write!(f, "No message at index {}, the thread has {}", idx, len)
+            }
+            Self::Conflict { idx } => write!(
+                f,
+                "Message {} changed since it was shown, reload the history and try again",
+                idx
+            ),
+            Self::NoVariant { idx, variant: None } => write!(f, "Message {} has no variants", idx),
+            Self::NoVariant {
+                idx,
+                variant: Some(variant),
+            } => write!(f, "Message {} has no variant {}", idx, variant),
         }
     }
 }
 
+impl std::error::Error for EditError {}
+
 #[derive(Debug, Clone)]
 pub struct CacheEdit {
     pub agent_id: String,
+    pub thread_id: u64,
     pub edit: StackEdit,
+    /// `message_hash` of the edit's target as the client saw it, `None` to skip the check
+    pub expected: Option<String>,
+}
+
+/// Tells whoever queued an edit whether the agent's cache took it
+pub type EditAckSender = oneshot::Sender<Result<(), EditError>>;
+
+/// An edit waiting for the listener to make it to the agent's cache
+#[derive(Debug)]
+pub struct QueuedEdit {
+    pub change: CacheEdit,
+    pub ack: Option<EditAckSender>,
+    /// How to keep the edit once the agent took it, `None` if the UI's copy has it already
+    pub record: Option<EditRecord>,
+}
+
+/// What's kept of an edit once it's made: who asked for it, and what it is to the undo history
+#[derive(Debug, Clone)]
+pub struct EditRecord {
+    pub client: Option<String>,
+    pub history: HistoryAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryAction {
+    /// A new edit, undone by its inverse
+    Record,
+    /// An undo, redone by its inverse
+    Undo,
+    /// A redo, undone again by its inverse
+    Redo,
+    /// Left out of the history, like the edits of a regeneration
+    Skip,
+}
+
+impl EditRecord {
+    /// Makes the edit to the UI's copy of the thread, then logs it, keeps it for undo and saves
+    /// the thread. Call once the edit is checked against the copy.
+    pub fn commit(
+        &self,
+        db: &Database,
+        env_id: &str,
+        agent_id: &str,
+        thread: &mut Thread,
+        edit: StackEdit,
+    ) -> Result<(), EditError> {
+        let inverse = edit.inverse(&thread.cache);
+        let event = AuditEvent::edit(
+            agent_id,
+            thread.id,
+            &edit,
+            &thread.cache,
+            self.client.as_deref(),
+        );
+        thread.track_edit(&edit);
+        edit.make_edit(&mut thread.cache)?;
+        if let Err(err) = db.append_audit(env_id, &event) {
+            tracing::error!("Could not log edit: {:?}", err);
+        }
+
+        let expected = inverse
+            .target()
+            .and_then(|idx| thread.cache.as_ref().get(idx))
+            .map(message_hash);
+        let inverse = HistoryEdit {
+            edit: inverse,
+            expected,
+        };
+        match self.history {
+            HistoryAction::Record => thread.history.record(inverse),
+            HistoryAction::Undo => thread.history.push_redo(inverse),
+            HistoryAction::Redo => thread.history.push_undo(inverse),
+            HistoryAction::Skip => {}
+        }
+        if let Err(err) = db.save_thread(env_id, agent_id, thread) {
+            tracing::error!("Could not save edited cache: {:?}", err);
+        }
+        Ok(())
+    }
+}
+
+impl CacheEdit {
+    /// Errors instead of making the edit to the wrong message or out of the cache's bounds
+    pub fn check(&self, cache: &MessageStack) -> Result<(), EditError> {
+        self.edit.check_bounds(cache)?;
+        if let (Some(expected), Some(idx)) = (&self.expected, self.edit.target()) {
+            let current = cache.as_ref().get(idx).map(message_hash);
+            if current.as_ref() != Some(expected) {
+                return Err(EditError::Conflict { idx });
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct UiUpdatesListener {
-    shared_cache_changes: Arc<RwLock<VecDeque<CacheEdit>>>,
-    shared_cache_states: Arc<RwLock<HashMap<String, MessageStack>>>,
+    shared_cache_changes: Arc<RwLock<VecDeque<QueuedEdit>>>,
+    shared_cache_states: Arc<RwLock<HashMap<String, AgentThreads>>>,
+    completion_clients: Arc<RwLock<HashMap<String, String>>>,
+    env_id: String,
+    db: Database,
+    channels: AgentChannels,
 }
 
 impl UiUpdatesListener {
     pub fn new(
-        shared_cache_changes: Arc<RwLock<VecDeque<CacheEdit>>>,
-        shared_cache_states: Arc<RwLock<HashMap<String, MessageStack>>>,
+        shared_cache_changes: Arc<RwLock<VecDeque<QueuedEdit>>>,
+        shared_cache_states: Arc<RwLock<HashMap<String, AgentThreads>>>,
+        completion_clients: Arc<RwLock<HashMap<String, String>>>,
+        env_id: &str,
+        db: Database,
+        channels: AgentChannels,
     ) -> Self {
         Self {
             shared_cache_changes,
             shared_cache_states,
+            completion_clients,
+            env_id: env_id.to_owned(),
+            db,
+            channels,
+        }
+    }
+}
+
+impl UiUpdatesListener {
+    /// Brings the UI's copy of the thread to the agent's cache as it was before the edit, then
+    /// makes and keeps the edit there too
+    fn commit(&self, change: &CacheEdit, before: MessageStack, record: &EditRecord) {
+        let mut states = self.shared_cache_states.write().unwrap();
+        let Some(thread) = states
+            .get_mut(&change.agent_id)
+            .and_then(|threads| threads.get_mut(change.thread_id))
+        else {
+            return;
+        };
+        thread.cache = before;
+        if let Err(err) = record.commit(
+            &self.db,
+            &self.env_id,
+            &change.agent_id,
+            thread,
+            change.edit.clone(),
+        ) {
+            tracing::error!(
+                "Could not make edit the agent took to the UI's copy: {}",
+                err
+            );
+        }
+    }
+
+    /// Logs the cache about to be sent, under the client that asked for it if it said so
+    fn log_completion(&self, agent_id: &str, dispatch: &Dispatch) {
+        let Ok(agent) = dispatch.get_agent_ref(agent_id) else {
+            return;
+        };
+        let Some(thread_id) = self
+            .shared_cache_states
+            .read()
+            .unwrap()
+            .get(agent_id)
+            .map(|threads| threads.active)
+        else {
+            return;
+        };
+        let client = self.completion_clients.write().unwrap().remove(agent_id);
+        let event = AuditEvent::completion(agent_id, thread_id, &agent.cache, client);
+        if let Err(err) = self.db.append_audit(&self.env_id, &event) {
+            tracing::error!("Could not log completion: {:?}", err);
         }
     }
 }
 
 impl EnvListener for UiUpdatesListener {
-    fn trigger<'l>(
-        &self,
-        env_message: &'l espionox::environment::dispatch::EnvMessage,
-    ) -> Option<&'l espionox::environment::dispatch::EnvMessage> {
+    fn trigger<'l>(&self, env_message: &'l EnvMessage) -> Option<&'l EnvMessage> {
         match env_message {
-            EnvMessage::Response(res) => {
-                if let EnvNotification::AgentStateUpdate { .. } = res {
-                    return Some(env_message);
-                }
-            }
-            EnvMessage::Request(r) => match r {
+            EnvMessage::Response(EnvNotification::AgentStateUpdate { .. })
+            | EnvMessage::Request(
                 EnvRequest::PushToCache { .. }
                 | EnvRequest::GetCompletion { .. }
                 | EnvRequest::GetAgentState { .. }
-                | EnvRequest::GetCompletionStreamHandle { .. } => {
-                    return Some(env_message);
-                }
-                _ => {}
-            },
-            _ => {}
+                | EnvRequest::GetCompletionStreamHandle { .. },
+            ) => Some(env_message),
+            _ => None,
         }
-        None
     }
     fn method<'l>(
         &'l mut self,
-        trigger_message: espionox::environment::dispatch::EnvMessage,
-        dispatch: &'l mut espionox::environment::dispatch::Dispatch,
-    ) -> espionox::environment::dispatch::listeners::ListenerMethodReturn {
+        trigger_message: EnvMessage,
+        dispatch: &'l mut Dispatch,
+    ) -> ListenerMethodReturn<'l> {
         Box::pin(async move {
             match trigger_message {
-                EnvMessage::Response(ref res) => {
-                    if let EnvNotification::AgentStateUpdate {
-                        ref cache,
-                        ref agent_id,
-                        ..
-                    } = res
-                    {
-                        tracing::info!("Agent: {}", agent_id);
-                        let mut caches = self.shared_cache_states.write().unwrap();
-                        caches.insert(agent_id.to_owned(), cache.clone());
-                        tracing::info!("Sent update");
-                        return Ok(trigger_message);
+                EnvMessage::Response(EnvNotification::AgentStateUpdate {
+                    ref cache,
+                    ref agent_id,
+                    ..
+                }) => {
+                    tracing::info!("Agent: {}", agent_id);
+                    let mut caches = self.shared_cache_states.write().unwrap();
+                    if let Some(threads) = caches.get_mut(agent_id) {
+                        let thread = threads.active_mut();
+                        thread.cache = cache.clone();
+                        if let Err(err) = self.db.save_thread(&self.env_id, agent_id, thread) {
+                            tracing::error!(
+                                "Could not save cache of agent '{}': {:?}",
+                                agent_id,
+                                err
+                            );
+                        }
+                        self.channels
+                            .history_changed(&self.env_id, agent_id, thread.id);
                     }
+                    tracing::info!("Sent update");
+                    Ok(trigger_message)
                 }
-                EnvMessage::Request(ref r) => match r {
+                EnvMessage::Request(
                     EnvRequest::PushToCache { .. }
                     | EnvRequest::GetCompletion { .. }
                     | EnvRequest::GetAgentState { .. }
-                    | EnvRequest::GetCompletionStreamHandle { .. } => {
-                        let mut cache_changes = self.shared_cache_changes.write().unwrap();
+                    | EnvRequest::GetCompletionStreamHandle { .. },
+                ) => {
+                    // Taken out first, the handler queues edits while holding the states' lock
+                    let queued: Vec<QueuedEdit> = self
+                        .shared_cache_changes
+                        .write()
+                        .unwrap()
+                        .drain(..)
+                        .collect();
 
-                        while let Some(change) = cache_changes.pop_front() {
-                            if let Some(agent) = dispatch.get_agent_mut(&change.agent_id).ok() {
-                                change.edit.make_edit(&mut agent.cache);
+                    for QueuedEdit {
+                        change,
+                        ack,
+                        record,
+                    } in queued
+                    {
+                        let Ok(agent) = dispatch.get_agent_mut(&change.agent_id) else {
+                            continue;
+                        };
+                        let before = record.is_some().then(|| agent.cache.clone());
+                        let result = change
+                            .check(&agent.cache)
+                            .and_then(|_| change.edit.clone().make_edit(&mut agent.cache));
+                        match &result {
+                            // Only kept once the agent has it, so a dropped edit leaves no trace
+                            Ok(()) => {
+                                if let (Some(record), Some(before)) = (&record, before) {
+                                    self.commit(&change, before, record);
+                                }
+                            }
+                            // The agent's cache moved on since the edit was checked
+                            Err(err) => {
+                                tracing::warn!(
+                                    "Dropped edit to agent '{}': {}",
+                                    change.agent_id,
+                                    err
+                                )
                             }
                         }
-                        return Ok(trigger_message);
+                        if let Some(ack) = ack {
+                            // Whoever queued it may have stopped waiting
+                            let _ = ack.send(result);
+                        }
+                    }
+                    if let EnvMessage::Request(
+                        EnvRequest::GetCompletion { ref agent_id, .. }
+                        | EnvRequest::GetCompletionStreamHandle { ref agent_id, .. },
+                    ) = trigger_message
+                    {
+                        self.log_completion(agent_id, dispatch);
                     }
-                    _ => {}
-                },
-                _ => {}
+                    Ok(trigger_message)
+                }
+                _ => Err(ListenerError::IncorrectTrigger),
             }
-
-            Err(espionox::environment::ListenerError::IncorrectTrigger)
         })
     }
 }
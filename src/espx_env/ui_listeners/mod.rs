@@ -3,4 +3,4 @@ mod listener;
 
 pub use handler::*;
 
-pub use listener::{CacheEdit, StackEdit, UiUpdatesListener};
+pub use listener::{message_hash, CacheEdit, EditError, StackEdit, UiUpdatesListener};
@@ -0,0 +1,180 @@
+use espionox::agents::memory::Message;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+};
+
+use super::{config::ModelPrice, EnvironmentState};
+use crate::database::Database;
+
+/// Roughly what OpenAI's chat format adds to each message for its role and separators
+const TOKENS_PER_MESSAGE: u64 = 4;
+
+/// Tokens used by one or more completions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub completions: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// Dollars, `None` if the model has no price
+    pub fn cost(&self, price: Option<&ModelPrice>) -> Option<f64> {
+        let price = price?;
+        Some(
+            (self.prompt_tokens as f64 * price.prompt
+                + self.completion_tokens as f64 * price.completion)
+                / 1_000_000.0,
+        )
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.completions += other.completions;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// Espionox doesn't report usage of streamed completions, so tokens are estimated locally at
+/// about four characters each, OpenAI's rule of thumb for english text
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+/// Tokens the message takes up in a prompt
+pub fn estimate_message_tokens(message: &Message) -> u64 {
+    estimate_tokens(&message.content) + TOKENS_PER_MESSAGE
+}
+
+/// Tokens of the context window last sent to each agent's model, by agent id. Written by the
+/// `ContextWindowListener` when it trims an agent's cache, only agents with a context window get
+/// an entry.
+pub type SentTokens = Arc<Mutex<HashMap<String, u64>>>;
+
+/// Counts the tokens of a completion, started once the prompt is known
+#[derive(Debug)]
+pub struct UsageMeter {
+    db: Database,
+    env_id: String,
+    agent_id: String,
+    thread_id: u64,
+    /// Estimate of the whole thread and prompt, used unless the context window was recorded
+    prompt_tokens: u64,
+    sent_tokens: SentTokens,
+}
+
+impl UsageMeter {
+    /// Adds the completion to its thread's totals and hands back what it used.
+    /// Cancelled replies count too, their tokens were generated all the same.
+    pub fn finish(self, reply: &str) -> Usage {
+        let prompt_tokens = self
+            .sent_tokens
+            .lock()
+            .unwrap()
+            .remove(&self.agent_id)
+            .unwrap_or(self.prompt_tokens);
+        let usage = Usage {
+            completions: 1,
+            prompt_tokens,
+            completion_tokens: estimate_tokens(reply),
+        };
+        tracing::info!("Completion used {:?}", usage);
+        if let Err(err) = self
+            .db
+            .add_usage(&self.env_id, &self.agent_id, self.thread_id, &usage)
+        {
+            tracing::error!("Could not save usage: {:?}", err);
+        }
+        usage
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadUsage {
+    pub thread_id: u64,
+    /// `None` once the thread is deleted, its usage is kept
+    pub name: Option<String>,
+    pub usage: Usage,
+    pub cost: Option<f64>,
+}
+
+/// Totals of an agent and of each of its threads
+#[derive(Debug, Serialize)]
+pub struct AgentUsage {
+    pub agent_id: String,
+    pub model: String,
+    pub usage: Usage,
+    pub cost: Option<f64>,
+    pub threads: Vec<ThreadUsage>,
+}
+
+impl EnvironmentState {
+    /// Meters a completion of the agent's active thread, whose cache is sent along with the prompt,
+    /// or only its context window if the agent has one
+    pub fn usage_meter(&self, agent_id: &str, prompt: &Message) -> Option<UsageMeter> {
+        let threads = self.ui_handler.get_threads_of_agent(agent_id)?;
+        // Left by a completion that never reached the model
+        self.sent_tokens.lock().unwrap().remove(agent_id);
+        let cache = threads.active().cache.as_ref();
+        let prompt_tokens = cache
+            .iter()
+            .chain(std::iter::once(prompt))
+            .map(estimate_message_tokens)
+            .sum();
+        Some(UsageMeter {
+            db: self.db.clone(),
+            env_id: self.id().to_owned(),
+            agent_id: agent_id.to_owned(),
+            thread_id: threads.active,
+            prompt_tokens,
+            sent_tokens: Arc::clone(&self.sent_tokens),
+        })
+    }
+
+    /// `None` if there's no agent by the id
+    pub fn usage(
+        &self,
+        agent_id: &str,
+        prices: &HashMap<String, ModelPrice>,
+    ) -> Result<Option<AgentUsage>, anyhow::Error> {
+        let Some(config) = self.config.agents.iter().find(|c| c.id == agent_id) else {
+            return Ok(None);
+        };
+        let Some(threads) = self.ui_handler.get_threads_of_agent(agent_id) else {
+            return Ok(None);
+        };
+        let price = prices.get(&config.model);
+
+        let mut total = Usage::default();
+        let threads = self
+            .db
+            .load_usage(self.id(), agent_id)?
+            .into_iter()
+            .map(|(thread_id, usage)| {
+                total += usage;
+                ThreadUsage {
+                    thread_id,
+                    name: threads.get(thread_id).map(|t| t.name.to_owned()),
+                    usage,
+                    cost: usage.cost(price),
+                }
+            })
+            .collect();
+        Ok(Some(AgentUsage {
+            agent_id: agent_id.to_owned(),
+            model: config.model.to_owned(),
+            usage: total,
+            cost: total.cost(price),
+            threads,
+        }))
+    }
+}
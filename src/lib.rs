@@ -0,0 +1,13 @@
+pub mod api;
+pub mod client;
+pub mod database;
+pub mod errors;
+pub mod espx_env;
+pub mod routing;
+pub mod state;
+pub mod telemetry;
+pub mod view_logic;
+pub mod websocket;
+
+pub use errors::AppError;
+pub use state::*;
@@ -1,15 +1,9 @@
-pub mod espx_env;
-pub mod routing;
-pub mod state;
-pub mod telemetry;
-pub mod view_logic;
-pub mod websocket;
-
-pub use state::*;
-
+use bureau_web::{
+    espx_env::config::BureauConfig, get_subscriber, init_subscriber, routing, AppState,
+};
 use once_cell::sync::Lazy;
-use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::RwLock;
 
 #[tokio::main]
 async fn main() {
@@ -27,17 +21,28 @@ async fn main() {
 
     Lazy::force(&TRACING);
 
-    // let _ = database::connect().await;
-    // database::test_get().await.unwrap();
-
-    let (tx, _rx) = broadcast::channel(100);
+    dotenv::dotenv().ok();
+    let config = match BureauConfig::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Could not load config: {:#}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let state = Arc::new(RwLock::new(AppState::init(tx).await));
+    let app_state = match AppState::init(&config).await {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("Could not init app state: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let state = Arc::new(RwLock::new(app_state));
 
     let router = routing::main_router().with_state(Arc::clone(&state));
 
     axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(router.into_make_service())
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
@@ -1,8 +1,8 @@
 use super::websocket as ws;
 use crate::{
-    patches,
+    agents, api, patches, threads,
     views::{self, models::LayoutTemplate},
-    SharedState,
+    AppError, SharedState,
 };
 
 use askama::Template;
@@ -10,7 +10,7 @@ use axum::{
     http::Request,
     middleware::{self, Next},
     response::{Html, IntoResponse, Response},
-    routing::{delete, get, patch},
+    routing::{delete, get, patch, post},
     Router,
 };
 use axum_htmx::extractors::HxRequest;
@@ -19,23 +19,83 @@ use tower_http::services::ServeDir;
 
 pub fn main_router() -> Router<SharedState> {
     let websocket_routes = init_ws_routes();
-    let agent_routes = init_agent_routes();
+    let env_routes = init_env_routes();
+    let api_routes = init_api_routes();
     Router::new()
         .route("/", get(views::templates::index))
-        .nest("/:agent_id", agent_routes)
+        .nest("/:env_id", env_routes)
         .layer(middleware::from_fn(non_hx_request_middleware))
         .nest("/ws", websocket_routes)
+        .route("/:env_id/:agent_id/stream", get(ws::sse::stream_handler))
+        .nest("/api/v1", api_routes)
+        .nest("/v1", init_openai_routes())
         .nest_service("/static", ServeDir::new("static"))
 }
 
+fn init_env_routes() -> Router<SharedState> {
+    Router::new()
+        .route("/agents", post(agents::create_agent))
+        .nest("/:agent_id", init_agent_routes())
+}
+
 fn init_agent_routes() -> Router<SharedState> {
     Router::new()
-        .route("/", get(views::partials::agent_view))
+        .route(
+            "/",
+            get(views::partials::agent_view).delete(agents::delete_agent),
+        )
+        .route("/usage", get(views::partials::usage))
+        .route("/audit", get(views::partials::audit_log))
+        .route("/prompt", post(ws::sse::prompt))
+        .route("/cancel", post(ws::sse::cancel))
+        .route("/regenerate", post(ws::sse::regenerate))
+        .route("/subscribe", post(ws::sse::subscribe))
+        .route("/threads", post(threads::create_thread))
+        .nest("/threads/:thread_id", init_thread_routes())
+}
+
+fn init_thread_routes() -> Router<SharedState> {
+    Router::new()
+        .route("/", delete(threads::delete_thread))
+        .route("/switch", patch(threads::switch_thread))
+        .route("/rename", patch(threads::rename_thread))
         .route("/history", get(views::partials::history))
         .route("/message_change/:index", patch(patches::message_change))
         .route("/message_delete/:index", delete(patches::message_delete))
+        .route("/message_variant/:index", patch(patches::message_variant))
+        .route("/message_pin/:index", patch(patches::message_pin))
+        .route("/message_insert/:index", patch(patches::message_insert))
+        .route("/message_move/:index", patch(patches::message_move))
+        .route("/message_role/:index", patch(patches::message_role))
+        .route("/truncate", patch(patches::truncate))
+        .route("/clear", patch(patches::clear))
         .route("/add_message", patch(patches::add_message))
         .route("/add_message_form", get(patches::add_message_form))
+        .route("/undo", patch(patches::undo))
+        .route("/redo", patch(patches::redo))
+}
+
+/// Answers with json whether or not the request came from htmx
+fn init_api_routes() -> Router<SharedState> {
+    Router::new()
+        .route("/openapi.json", get(api::openapi))
+        .route("/agents", get(api::agents::list_agents))
+        .route("/:env_id/:agent_id/prompt", post(api::prompt::prompt))
+        .route(
+            "/:env_id/:agent_id/threads/:thread_id/messages",
+            get(api::messages::history),
+        )
+        .route(
+            "/:env_id/:agent_id/threads/:thread_id/edits",
+            post(api::messages::edit),
+        )
+}
+
+/// The part of OpenAI's api its SDKs need to chat, for agents to stand in for its models
+fn init_openai_routes() -> Router<SharedState> {
+    Router::new()
+        .route("/models", get(api::openai::list_models))
+        .route("/chat/completions", post(api::openai::chat_completions))
 }
 
 fn init_ws_routes() -> Router<SharedState> {
@@ -59,12 +119,15 @@ async fn non_hx_request_middleware<B>(
         tracing::info!("HxRequest header not present, middleware returning HTML...");
         let path_and_params = Some((path, params));
         let template = LayoutTemplate {
-            agent_names: None,
+            environments: None,
             path_and_params,
         };
-        return Html(template.render().unwrap()).into_response();
+        return match template.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(err) => AppError::from(err).into_response(),
+        };
     }
 
     tracing::info!("HxRequest header present, passing through middleware...");
-    next.run(req).await.into()
+    next.run(req).await
 }
@@ -1,22 +1,75 @@
-use super::espx_env::EnvironmentState;
 pub use super::telemetry::*;
 pub use super::view_logic::*;
+use super::{
+    database::Database,
+    errors::AppError,
+    espx_env::{
+        config::{BureauConfig, ModelPrice, Transport},
+        EnvironmentState,
+    },
+    websocket::{channels::AgentChannels, in_flight::InFlight, sse::SseConnections},
+};
 
-use axum::response::Html;
-use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use anyhow::Context;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use tokio::sync::RwLock;
 
 #[derive(Debug)]
 pub struct AppState {
-    pub env_state: EnvironmentState,
-    pub tx: broadcast::Sender<Html<String>>,
+    /// In the order they are declared in the config
+    pub environments: Vec<EnvironmentState>,
+    /// Where agents' replies and notices go out to the connections subscribed to them
+    pub channels: AgentChannels,
+    pub in_flight: InFlight,
+    /// Open server sent event streams, which prompts are posted for
+    pub sse_connections: SseConnections,
+    /// By model name
+    pub prices: HashMap<String, ModelPrice>,
+    pub transport: Transport,
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 pub type SharedState = Arc<RwLock<AppState>>;
 
 impl AppState {
-    pub async fn init(tx: broadcast::Sender<Html<String>>) -> Self {
-        let env_state = EnvironmentState::init().await.expect("Could not init env");
-        Self { env_state, tx }
+    pub async fn init(config: &BureauConfig) -> Result<Self, anyhow::Error> {
+        let db = Database::connect(&config.database)?;
+        let channels = AgentChannels::default();
+        let mut environments = vec![];
+        for env_config in config.environments.iter() {
+            let env_state = EnvironmentState::init(env_config, db.clone(), channels.clone())
+                .await
+                .with_context(|| format!("Environment '{}'", env_config.id))?;
+            environments.push(env_state);
+        }
+        Ok(Self {
+            environments,
+            channels,
+            in_flight: InFlight::default(),
+            sse_connections: SseConnections::default(),
+            prices: config.prices.clone(),
+            transport: config.transport,
+            trusted_proxies: config.trusted_proxies.clone(),
+        })
+    }
+
+    pub fn env_state(&self, env_id: &str) -> Result<&EnvironmentState, AppError> {
+        self.environments
+            .iter()
+            .find(|e| e.id() == env_id)
+            .ok_or(AppError::NotFound(format!(
+                "No environment with id '{}'",
+                env_id
+            )))
+    }
+
+    pub fn env_state_mut(&mut self, env_id: &str) -> Result<&mut EnvironmentState, AppError> {
+        self.environments
+            .iter_mut()
+            .find(|e| e.id() == env_id)
+            .ok_or(AppError::NotFound(format!(
+                "No environment with id '{}'",
+                env_id
+            )))
     }
 }
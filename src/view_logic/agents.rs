@@ -0,0 +1,150 @@
+use crate::{
+    espx_env::{
+        config::{AgentConfig, AgentParams, Provider},
+        EnvironmentState,
+    },
+    websocket::protocol::WsResponse,
+    AppError, AppState, SharedState,
+};
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    response::{Html, IntoResponse, Response},
+    Form,
+};
+use axum_htmx::{HxReswap, HxRetarget, SwapOption};
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "agent_list.html")]
+pub struct AgentList {
+    pub env_id: String,
+    pub agent_names: Vec<String>,
+}
+
+impl From<&EnvironmentState> for AgentList {
+    fn from(env_state: &EnvironmentState) -> Self {
+        Self {
+            env_id: env_state.id().to_string(),
+            agent_names: env_state.agent_names(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewAgent {
+    id: String,
+    system_prompt: String,
+    provider: Provider,
+    model: String,
+    /// Empty when the field is left blank
+    temperature: String,
+}
+
+impl TryFrom<NewAgent> for AgentConfig {
+    type Error = anyhow::Error;
+    fn try_from(form: NewAgent) -> Result<Self, Self::Error> {
+        let temperature = match form.temperature.trim() {
+            "" => None,
+            t => Some(
+                t.parse()
+                    .map_err(|_| anyhow::anyhow!("Temperature must be between 0 and 200"))?,
+            ),
+        };
+        let system_prompt = match form.system_prompt.trim() {
+            "" => None,
+            p => Some(p.to_string()),
+        };
+        Ok(AgentConfig {
+            id: form.id.trim().to_string(),
+            system_prompt,
+            provider: form.provider,
+            model: form.model,
+            params: AgentParams {
+                temperature,
+                ..Default::default()
+            },
+            responses: vec![],
+            token_delay_ms: None,
+            context: None,
+        })
+    }
+}
+
+/// Errors are swapped into the header under the environment's new agent form instead of its agent list
+fn agent_list_error(env_id: &str, err: AppError) -> Response {
+    (
+        HxRetarget(format!("#new-agent-error-{}", env_id)),
+        HxReswap(SwapOption::InnerHtml),
+        err,
+    )
+        .into_response()
+}
+
+/// Changing the agents rebuilds the environment, which would drop the replies being streamed in it
+fn refuse_while_replying(state: &AppState, env_id: &str) -> Result<(), AppError> {
+    match state.in_flight.any_in_env(env_id) {
+        true => Err(AppError::conflict(format!(
+            "Agents of environment '{}' are replying, try again once they're done",
+            env_id
+        ))),
+        false => Ok(()),
+    }
+}
+
+#[tracing::instrument(name = "Create agent", skip(state))]
+pub async fn create_agent(
+    State(state): State<SharedState>,
+    Path(env_id): Path<String>,
+    Form(new_agent): Form<NewAgent>,
+) -> Response {
+    let result = async {
+        let config = AgentConfig::try_from(new_agent).map_err(AppError::bad_request)?;
+        let mut state_write = state.write().await;
+        refuse_while_replying(&state_write, &env_id)?;
+        let env_state = state_write.env_state_mut(&env_id)?;
+        env_state
+            .insert_agent(config)
+            .await
+            .map_err(AppError::bad_request)?;
+        Ok::<_, AppError>(Html(AgentList::from(&*env_state).render()?))
+    }
+    .await;
+    result
+        .map_err(|err| agent_list_error(&env_id, err.context("Error creating agent")))
+        .into_response()
+}
+
+#[tracing::instrument(name = "Delete agent", skip(state))]
+pub async fn delete_agent(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+) -> Response {
+    let result = async {
+        let mut state_write = state.write().await;
+        refuse_while_replying(&state_write, &env_id)?;
+        let env_state = state_write.env_state_mut(&env_id)?;
+        env_state
+            .remove_agent(&agent_id)
+            .await
+            .map_err(AppError::not_found)?;
+        let list = AgentList::from(&*env_state);
+
+        state_write.channels.send(
+            &env_id,
+            &agent_id,
+            None,
+            WsResponse::AgentRemoved {
+                env_id: env_id.clone(),
+                agent_id: agent_id.clone(),
+            },
+        );
+        state_write.channels.remove(&env_id, &agent_id);
+
+        Ok::<_, AppError>(Html(list.render()?))
+    }
+    .await;
+    result
+        .map_err(|err| agent_list_error(&env_id, err.context("Error removing agent")))
+        .into_response()
+}
@@ -1,2 +1,4 @@
+pub mod agents;
 pub mod patches;
+pub mod threads;
 pub mod views;
@@ -1,6 +1,8 @@
+use super::views::partials::render_history;
 use crate::{
-    espx_env::ui_listeners::{CacheEdit, StackEdit},
-    SharedState,
+    client::Client,
+    espx_env::ui_listeners::{CacheEdit, EditAck, EditError, StackEdit},
+    AppError, SharedState,
 };
 use askama::Template;
 use axum::{
@@ -8,17 +10,20 @@ use axum::{
     response::Html,
     Form,
 };
-use espionox::{
-    agents::{memory::Message, Agent},
-    language_models::LLM,
-};
+use espionox::agents::memory::{Message, MessageRole};
 use serde::Deserialize;
 use std::collections::HashMap;
 
 #[derive(Template)]
 #[template(path = "add_message_form.html")]
 pub struct AddMessageForm<'a> {
+    env_id: &'a str,
     agent_id: &'a str,
+    thread_id: u64,
+    /// Where the message is inserted, `None` pushes it to the end
+    index: Option<usize>,
+    /// Hash of the message it's inserted above
+    hash: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -27,92 +32,364 @@ pub struct AddMessage {
     content: String,
 }
 
+impl TryFrom<AddMessage> for Message {
+    type Error = AppError;
+    fn try_from(add_message: AddMessage) -> Result<Self, Self::Error> {
+        let role = MessageRole::try_from(add_message.role)
+            .map_err(|err| AppError::bad_request(err).context("Error updating cache"))?;
+        Ok(Message {
+            role,
+            content: add_message.content,
+        })
+    }
+}
+
 #[tracing::instrument(name = "Add message to agent", skip_all)]
 pub async fn add_message(
     State(state): State<SharedState>,
-    Path(agent_id): Path<String>,
+    client: Client,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
     Form(add_message): Form<AddMessage>,
-) -> Html<String> {
-    let mut state_write = state.write().await;
-    let message = Message {
-        role: add_message.role.try_into().unwrap(),
-        content: add_message.content,
-    };
+) -> Result<Html<String>, AppError> {
     let edit = CacheEdit {
-        agent_id: agent_id.clone(),
-        edit: StackEdit::PushMessageToCache { message },
+        agent_id,
+        thread_id,
+        edit: StackEdit::PushMessageToCache {
+            message: add_message.try_into()?,
+        },
+        expected: None,
     };
+    edit_and_render(&state, &env_id, edit, &client).await
+}
 
-    match state_write.env_state.ui_handler.push_to_changes(edit) {
-        Ok(_) => {
-            return Html(String::from("Cache Updated!"));
-        }
-        Err(err) => {
-            tracing::info!("Error updating cache: {:?} ", err);
-            return Html(format!("Error updating cache: {:?} ", err));
-        }
-    }
+#[derive(Deserialize, Debug)]
+pub struct AddMessageFormParams {
+    index: Option<usize>,
+    hash: Option<String>,
 }
 
-pub async fn add_message_form(Path(agent_id): Path<String>) -> Html<String> {
+pub async fn add_message_form(
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+    Query(params): Query<AddMessageFormParams>,
+) -> Result<Html<String>, AppError> {
     let form = AddMessageForm {
+        env_id: &env_id,
         agent_id: &agent_id,
+        thread_id,
+        index: params.index,
+        hash: params.hash,
     };
-    Html(form.render().unwrap())
+    Ok(Html(form.render()?))
 }
 
 #[tracing::instrument(name = "Change message", skip_all)]
 pub async fn message_change(
     State(state): State<SharedState>,
-    Path((agent_id, idx)): Path<(String, usize)>,
+    client: Client,
+    Path((env_id, agent_id, thread_id, idx)): Path<(String, String, u64, usize)>,
     Query(params): Query<HashMap<String, String>>,
-) -> Html<String> {
-    let mut state_write = state.write().await;
-    if let Some(new_text) = params.get("change") {
-        let edit = CacheEdit {
-            agent_id,
-            edit: StackEdit::EditMessageInCache {
-                idx,
-                new_text: new_text.to_string(),
-            },
-        };
-
-        match state_write.env_state.ui_handler.push_to_changes(edit) {
-            Ok(_) => {
-                tracing::info!("Returning success message");
-
-                return Html(String::from("Cache Updated!"));
-            }
-            Err(err) => {
-                tracing::info!("Error updating cache: {:?} ", err);
-                return Html(format!("Error updating cache: {:?} ", err));
-            }
-        }
-    }
-
-    return Html(String::from(
+    Query(expected): Query<Expected>,
+) -> Result<Html<String>, AppError> {
+    let new_text = params.get("change").ok_or(AppError::bad_request(
         "Error updating cache: no change passed in request",
-    ));
+    ))?;
+    let edit = CacheEdit {
+        agent_id,
+        thread_id,
+        edit: StackEdit::EditMessageInCache {
+            idx,
+            new_text: new_text.to_string(),
+        },
+        expected: expected.hash,
+    };
+    edit_and_render(&state, &env_id, edit, &client).await
 }
 
 #[tracing::instrument(name = "Delete message", skip_all)]
 pub async fn message_delete(
     State(state): State<SharedState>,
-    Path((agent_id, idx)): Path<(String, usize)>,
-) -> Html<String> {
-    let mut state_write = state.write().await;
+    client: Client,
+    Path((env_id, agent_id, thread_id, idx)): Path<(String, String, u64, usize)>,
+    Query(expected): Query<Expected>,
+) -> Result<Html<String>, AppError> {
     let edit = CacheEdit {
         agent_id,
+        thread_id,
         edit: StackEdit::RemoveMessageInCache { idx },
+        expected: expected.hash,
     };
-    match state_write.env_state.ui_handler.push_to_changes(edit) {
-        Ok(_) => {
-            tracing::info!("Delete successful");
-            return Html(String::from("Delete successful"));
-        }
-        Err(err) => {
-            tracing::info!("Error updating cache: {:?} ", err);
-            return Html(format!("Error updating cache: {:?} ", err));
+    edit_and_render(&state, &env_id, edit, &client).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SelectVariant {
+    variant: usize,
+}
+
+/// Swaps a regenerated reply for another of its variants and returns the updated history
+#[tracing::instrument(name = "Select message variant", skip(state))]
+pub async fn message_variant(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id, idx)): Path<(String, String, u64, usize)>,
+    Query(select): Query<SelectVariant>,
+) -> Result<Html<String>, AppError> {
+    let ack = state
+        .write()
+        .await
+        .env_state_mut(&env_id)?
+        .ui_handler
+        .select_variant(
+            &agent_id,
+            thread_id,
+            idx,
+            select.variant,
+            Some(client.as_str()),
+        )
+        .map_err(|err| edit_failed(err).context("Error selecting variant"))?;
+    confirm_and_render(&state, &env_id, &agent_id, thread_id, ack).await
+}
+
+/// Pins or unpins a message, pinned messages stay in the agent's context window
+#[tracing::instrument(name = "Toggle message pin", skip(state))]
+pub async fn message_pin(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id, thread_id, idx)): Path<(String, String, u64, usize)>,
+) -> Result<Html<String>, AppError> {
+    let mut state_write = state.write().await;
+    let transport = state_write.transport;
+    let env_state = state_write.env_state_mut(&env_id)?;
+    env_state
+        .ui_handler
+        .toggle_pin(&agent_id, thread_id, idx)
+        .map_err(|err| edit_failed(err).context("Error pinning message"))?;
+    render_history(env_state, &agent_id, thread_id, transport)
+}
+
+/// Inserts a message before the one at the index and returns the updated history
+#[tracing::instrument(name = "Insert message", skip_all)]
+pub async fn message_insert(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id, idx)): Path<(String, String, u64, usize)>,
+    Query(expected): Query<Expected>,
+    Form(add_message): Form<AddMessage>,
+) -> Result<Html<String>, AppError> {
+    let edit = CacheEdit {
+        agent_id,
+        thread_id,
+        edit: StackEdit::InsertAt {
+            idx,
+            message: add_message.try_into()?,
+        },
+        expected: expected.hash,
+    };
+    edit_and_render(&state, &env_id, edit, &client).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MoveMessage {
+    to: usize,
+}
+
+#[tracing::instrument(name = "Move message", skip(state))]
+pub async fn message_move(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id, from)): Path<(String, String, u64, usize)>,
+    Query(params): Query<MoveMessage>,
+    Query(expected): Query<Expected>,
+) -> Result<Html<String>, AppError> {
+    let edit = CacheEdit {
+        agent_id,
+        thread_id,
+        edit: StackEdit::Move {
+            from,
+            to: params.to,
+        },
+        expected: expected.hash,
+    };
+    edit_and_render(&state, &env_id, edit, &client).await
+}
+
+/// Sent by the role select of each message
+#[derive(Deserialize, Debug)]
+pub struct ChangeRole {
+    role: String,
+}
+
+#[tracing::instrument(name = "Change message role", skip(state))]
+pub async fn message_role(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id, idx)): Path<(String, String, u64, usize)>,
+    Query(expected): Query<Expected>,
+    Form(params): Form<ChangeRole>,
+) -> Result<Html<String>, AppError> {
+    let role = MessageRole::try_from(params.role)
+        .map_err(|err| AppError::bad_request(err).context("Error changing role"))?;
+    let edit = CacheEdit {
+        agent_id,
+        thread_id,
+        edit: StackEdit::ChangeRole { idx, role },
+        expected: expected.hash,
+    };
+    edit_and_render(&state, &env_id, edit, &client).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Truncate {
+    len: usize,
+}
+
+/// Drops every message past the first `len`
+#[tracing::instrument(name = "Truncate messages", skip(state))]
+pub async fn truncate(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+    Query(params): Query<Truncate>,
+    Query(expected): Query<Expected>,
+) -> Result<Html<String>, AppError> {
+    let edit = CacheEdit {
+        agent_id,
+        thread_id,
+        edit: StackEdit::Truncate { len: params.len },
+        expected: expected.hash,
+    };
+    edit_and_render(&state, &env_id, edit, &client).await
+}
+
+#[tracing::instrument(name = "Clear messages", skip(state))]
+pub async fn clear(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+) -> Result<Html<String>, AppError> {
+    let edit = CacheEdit {
+        agent_id,
+        thread_id,
+        edit: StackEdit::Clear,
+        expected: None,
+    };
+    edit_and_render(&state, &env_id, edit, &client).await
+}
+
+/// Reverses the thread's latest edit and returns the updated history
+#[tracing::instrument(name = "Undo", skip(state))]
+pub async fn undo(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+) -> Result<Html<String>, AppError> {
+    let ack = state
+        .write()
+        .await
+        .env_state_mut(&env_id)?
+        .ui_handler
+        .undo(&agent_id, thread_id, Some(client.as_str()))
+        .map_err(|err| edit_failed(err).context("Error undoing edit"))?
+        .unwrap_or_default();
+    confirm_and_render(&state, &env_id, &agent_id, thread_id, ack).await
+}
+
+/// Makes the thread's latest undone edit again and returns the updated history
+#[tracing::instrument(name = "Redo", skip(state))]
+pub async fn redo(
+    State(state): State<SharedState>,
+    client: Client,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+) -> Result<Html<String>, AppError> {
+    let ack = state
+        .write()
+        .await
+        .env_state_mut(&env_id)?
+        .ui_handler
+        .redo(&agent_id, thread_id, Some(client.as_str()))
+        .map_err(|err| edit_failed(err).context("Error redoing edit"))?
+        .unwrap_or_default();
+    confirm_and_render(&state, &env_id, &agent_id, thread_id, ack).await
+}
+
+/// `message_hash` of the message an edit is aimed at, as the history showed it
+#[derive(Deserialize, Debug)]
+pub struct Expected {
+    hash: Option<String>,
+}
+
+/// Edits address an agent and thread that may not exist, so the UI handler's errors are not found
+/// errors, unless the edit itself is at fault: aimed at a message that changed since the client
+/// was shown it, or at an index or variant the thread doesn't have
+pub fn edit_failed(err: anyhow::Error) -> AppError {
+    match err.downcast_ref::<EditError>() {
+        Some(EditError::Conflict { .. }) => AppError::conflict(err),
+        Some(EditError::OutOfBounds { .. } | EditError::NoVariant { .. }) => {
+            AppError::bad_request(err)
         }
+        None => AppError::not_found(err),
+    }
+}
+
+/// Makes the edit and returns the thread's history as the agent has it afterwards
+async fn edit_and_render(
+    state: &SharedState,
+    env_id: &str,
+    edit: CacheEdit,
+    client: &Client,
+) -> Result<Html<String>, AppError> {
+    let (agent_id, thread_id) = (edit.agent_id.to_owned(), edit.thread_id);
+    let ack = state
+        .write()
+        .await
+        .env_state_mut(env_id)?
+        .ui_handler
+        .push_to_changes(edit, Some(client.as_str()))
+        .map_err(|err| edit_failed(err).context("Error updating cache"))?;
+    confirm_and_render(state, env_id, &agent_id, thread_id, ack).await
+}
+
+/// Renders the thread's history once the edit reached the agent
+async fn confirm_and_render(
+    state: &SharedState,
+    env_id: &str,
+    agent_id: &str,
+    thread_id: u64,
+    ack: EditAck,
+) -> Result<Html<String>, AppError> {
+    confirm_edit(state, env_id, agent_id, ack).await?;
+    let state_read = state.read().await;
+    render_history(
+        state_read.env_state(env_id)?,
+        agent_id,
+        thread_id,
+        state_read.transport,
+    )
+}
+
+/// Waits for the edit to reach the agent without holding on to the state, so it isn't locked
+/// while the environment gets to the edit
+pub async fn confirm_edit(
+    state: &SharedState,
+    env_id: &str,
+    agent_id: &str,
+    ack: EditAck,
+) -> Result<(), AppError> {
+    if !ack.is_pending() {
+        return Ok(());
     }
+    let link = state
+        .write()
+        .await
+        .env_state_mut(env_id)?
+        .agent_link(agent_id)?
+        .ok_or(AppError::not_found(format!(
+            "No agent with id '{}'",
+            agent_id
+        )))?;
+    link.apply_edits(ack)
+        .await
+        .map_err(|err| match err.downcast_ref::<EditError>() {
+            Some(_) => edit_failed(err).context("Error updating agent"),
+            None => AppError::from(err),
+        })
 }
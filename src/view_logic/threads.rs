@@ -0,0 +1,117 @@
+use crate::{views::partials::render_agent_view, AppError, SharedState};
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    Form,
+};
+use axum_htmx::{HxPrompt, HxReswap, HxRetarget, SwapOption};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+pub struct NewThread {
+    name: String,
+}
+
+/// Errors are swapped into the header above the thread list instead of the agent view
+fn thread_error(err: AppError) -> Response {
+    (
+        HxRetarget("#thread-error".to_string()),
+        HxReswap(SwapOption::InnerHtml),
+        err,
+    )
+        .into_response()
+}
+
+fn thread_name(name: &str) -> Result<&str, AppError> {
+    match name.trim() {
+        "" => Err(AppError::bad_request("Thread name cannot be empty")),
+        name => Ok(name),
+    }
+}
+
+#[tracing::instrument(name = "Create thread", skip(state))]
+pub async fn create_thread(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Form(new_thread): Form<NewThread>,
+) -> Response {
+    let result = async {
+        let name = thread_name(&new_thread.name)?;
+        let mut state_write = state.write().await;
+        let transport = state_write.transport;
+        let env_state = state_write.env_state_mut(&env_id)?;
+        env_state
+            .create_thread(&agent_id, name)
+            .map_err(AppError::bad_request)?;
+        render_agent_view(env_state, &agent_id, transport)
+    }
+    .await;
+    result
+        .map_err(|err| thread_error(err.context("Error creating thread")))
+        .into_response()
+}
+
+#[tracing::instrument(name = "Switch thread", skip(state))]
+pub async fn switch_thread(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+) -> Response {
+    let result = async {
+        let mut state_write = state.write().await;
+        let transport = state_write.transport;
+        let env_state = state_write.env_state_mut(&env_id)?;
+        env_state
+            .ui_handler
+            .switch_thread(&agent_id, thread_id)
+            .map_err(AppError::bad_request)?;
+        render_agent_view(env_state, &agent_id, transport)
+    }
+    .await;
+    result
+        .map_err(|err| thread_error(err.context("Error switching thread")))
+        .into_response()
+}
+
+#[tracing::instrument(name = "Rename thread", skip(state))]
+pub async fn rename_thread(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+    HxPrompt(name): HxPrompt,
+) -> Response {
+    let result = async {
+        let name = thread_name(name.as_deref().unwrap_or_default())?;
+        let mut state_write = state.write().await;
+        let transport = state_write.transport;
+        let env_state = state_write.env_state_mut(&env_id)?;
+        env_state
+            .ui_handler
+            .rename_thread(&agent_id, thread_id, name)
+            .map_err(AppError::bad_request)?;
+        render_agent_view(env_state, &agent_id, transport)
+    }
+    .await;
+    result
+        .map_err(|err| thread_error(err.context("Error renaming thread")))
+        .into_response()
+}
+
+#[tracing::instrument(name = "Delete thread", skip(state))]
+pub async fn delete_thread(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+) -> Response {
+    let result = async {
+        let mut state_write = state.write().await;
+        let transport = state_write.transport;
+        let env_state = state_write.env_state_mut(&env_id)?;
+        env_state
+            .ui_handler
+            .delete_thread(&agent_id, thread_id)
+            .map_err(AppError::bad_request)?;
+        render_agent_view(env_state, &agent_id, transport)
+    }
+    .await;
+    result
+        .map_err(|err| thread_error(err.context("Error deleting thread")))
+        .into_response()
+}
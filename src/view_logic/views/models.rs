@@ -1,3 +1,13 @@
+use crate::{
+    agents::AgentList,
+    espx_env::{
+        audit::{AuditEntry, AuditFilter, AuditKind},
+        config::Transport,
+        threads::Thread,
+        ui_listeners::message_hash,
+        usage::AgentUsage,
+    },
+};
 use askama::Template;
 use espionox::agents::memory::{Message, MessageRole};
 use markdown::to_html;
@@ -10,46 +20,74 @@ pub struct IndexTemplate;
 #[derive(Template)]
 #[template(path = "layout.html")]
 pub struct LayoutTemplate<'a> {
-    pub agent_names: Option<Vec<String>>,
+    pub environments: Option<Vec<AgentList>>,
     pub path_and_params: Option<(&'a str, &'a str)>,
 }
 
 #[derive(Template)]
 #[template(path = "agent_view.html")]
 pub struct AgentView<'a> {
+    pub env_id: &'a str,
     pub agent_id: &'a str,
+    pub active_thread: u64,
+    pub threads: Vec<Thread>,
+    pub transport: Transport,
+}
+
+#[derive(Template)]
+#[template(path = "usage.html")]
+pub struct UsageView {
+    pub report: AgentUsage,
+}
+
+#[derive(Template)]
+#[template(path = "audit_log.html")]
+pub struct AuditLogView {
+    pub env_id: String,
+    pub agent_id: String,
+    pub entries: Vec<AuditEntry>,
+    pub filter: AuditFilter,
 }
 
 #[derive(Template)]
 #[template(path = "chat_history.html")]
 pub struct ChatHistory {
+    pub env_id: String,
     pub agent_id: String,
+    pub thread_id: u64,
     pub messages: Vec<MessageRender>,
+    pub can_undo: bool,
+    pub can_redo: bool,
+    /// How the regenerate button sends its request
+    pub transport: Transport,
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct MessageRender {
     pub class: String,
     pub content: String,
+    /// Regenerated replies can be paged between when their variants were kept
+    #[serde(default)]
+    pub selected_variant: usize,
+    #[serde(default)]
+    pub variant_count: usize,
+    /// Left out of what the agent is sent once its context window is full
+    #[serde(default)]
+    pub outside_window: bool,
+    #[serde(default)]
+    pub pinned: bool,
+    /// `message_hash` of the message, sent back with edits so they don't land on a message that
+    /// changed or moved since it was shown
+    #[serde(default)]
+    pub hash: String,
 }
 
-impl Into<Message> for MessageRender {
-    fn into(self) -> Message {
-        match self.class.as_str() {
-            "user-message" => Message::new_user(&self.content),
-            "assistant-message" => Message::new_assistant(&self.content),
-            "system-message" => Message::new_system(&self.content),
-            _ => unreachable!(),
-        }
-    }
-}
-
-impl MessageRender {
-    fn role(&self) -> &str {
-        match self.class.as_str() {
-            "user-message" => "user",
-            "assistant-message" => "assistant",
-            "system-message" => "system",
+impl From<MessageRender> for Message {
+    fn from(render: MessageRender) -> Self {
+        match render.class.as_str() {
+            "user-message" => Message::new_user(&render.content),
+            "assistant-message" => Message::new_assistant(&render.content),
+            "system-message" => Message::new_system(&render.content),
             _ => unreachable!(),
         }
     }
@@ -73,6 +111,11 @@ impl From<&Message> for MessageRender {
         Self {
             class,
             content: sani,
+            selected_variant: 0,
+            variant_count: 0,
+            outside_window: false,
+            pinned: false,
+            hash: message_hash(m),
         }
     }
 }
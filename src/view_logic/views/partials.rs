@@ -1,35 +1,177 @@
-use crate::SharedState;
+use crate::{
+    espx_env::{audit::AuditFilter, config::Transport, context_window::Window, EnvironmentState},
+    websocket::protocol::WsFormat,
+    AppError, SharedState,
+};
 use askama::Template;
 use axum::{
-    extract::{Path, State},
-    response::Html,
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse, Response},
+    Json,
 };
+use serde::Deserialize;
 
-use super::models::{AgentView, ChatHistory, MessageRender};
+use super::models::{AgentView, AuditLogView, ChatHistory, MessageRender, UsageView};
 
-pub async fn agent_view(
-    Path(agent_id): Path<String>,
-    // State(state): State<SharedState>,
-) -> Html<String> {
+/// Renders the agent's view with its thread list, opened on the active thread
+pub fn render_agent_view(
+    env_state: &EnvironmentState,
+    agent_id: &str,
+    transport: Transport,
+) -> Result<Html<String>, AppError> {
+    let threads = env_state
+        .ui_handler
+        .get_threads_of_agent(agent_id)
+        .ok_or(AppError::NotFound(format!(
+            "No agent with id '{}'",
+            agent_id
+        )))?;
     let view = AgentView {
-        agent_id: &agent_id,
+        env_id: env_state.id(),
+        agent_id,
+        active_thread: threads.active,
+        threads: threads.threads,
+        transport,
     };
-    Html(view.render().unwrap())
+    Ok(Html(view.render()?))
 }
 
-#[tracing::instrument(name = "Agent history", skip(state))]
+pub async fn agent_view(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+) -> Result<Html<String>, AppError> {
+    let state_read = state.read().await;
+    render_agent_view(
+        state_read.env_state(&env_id)?,
+        &agent_id,
+        state_read.transport,
+    )
+}
+
+/// Renders the thread's messages, with paging for the ones that kept variants and the ones
+/// outside the agent's context window marked
+pub fn render_history(
+    env_state: &EnvironmentState,
+    agent_id: &str,
+    thread_id: u64,
+    transport: Transport,
+) -> Result<Html<String>, AppError> {
+    let thread = env_state
+        .ui_handler
+        .get_thread(agent_id, thread_id)
+        .ok_or(AppError::NotFound(format!(
+            "No thread {} for agent '{}'",
+            thread_id, agent_id
+        )))?;
+    tracing::info!("Got thread reference");
+    let window = env_state
+        .context_config(agent_id)
+        .map(|config| Window::compute(thread.cache.as_ref(), &thread.pinned, &config))
+        .unwrap_or_default();
+    let messages: Vec<MessageRender> = thread
+        .cache
+        .as_ref()
+        .iter()
+        .enumerate()
+        .map(|(idx, m)| {
+            let mut render = MessageRender::from(m);
+            if let Some(variants) = thread.variants.get(&idx) {
+                render.selected_variant = variants.selected;
+                render.variant_count = variants.contents.len();
+            }
+            render.outside_window = window.dropped.contains(&idx);
+            render.pinned = thread.pinned.contains(&idx);
+            render
+        })
+        .collect();
+    let history = ChatHistory {
+        env_id: env_state.id().to_owned(),
+        agent_id: agent_id.to_owned(),
+        thread_id,
+        messages,
+        can_undo: thread.history.can_undo(),
+        can_redo: thread.history.can_redo(),
+        transport,
+    };
+    Ok(Html(history.render()?))
+}
+
+#[tracing::instrument(name = "Thread history", skip(state))]
 pub async fn history(
     State(state): State<SharedState>,
-    Path(agent_id): Path<String>,
-) -> Html<String> {
+    Path((env_id, agent_id, thread_id)): Path<(String, String, u64)>,
+) -> Result<Html<String>, AppError> {
+    let state_read = state.read().await;
+    render_history(
+        state_read.env_state(&env_id)?,
+        &agent_id,
+        thread_id,
+        state_read.transport,
+    )
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UsageParams {
+    #[serde(default)]
+    format: WsFormat,
+}
+
+/// Tokens and cost of the agent and each of its threads, as json with `?format=json`
+#[tracing::instrument(name = "Agent usage", skip(state))]
+pub async fn usage(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Query(params): Query<UsageParams>,
+) -> Result<Response, AppError> {
+    let state_read = state.read().await;
+    let report = state_read
+        .env_state(&env_id)?
+        .usage(&agent_id, &state_read.prices)?
+        .ok_or(AppError::NotFound(format!(
+            "No agent with id '{}'",
+            agent_id
+        )))?;
+    Ok(match params.format {
+        WsFormat::Json => Json(report).into_response(),
+        WsFormat::Html => Html(UsageView { report }.render()?).into_response(),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditParams {
+    #[serde(default)]
+    format: WsFormat,
+    #[serde(flatten)]
+    filter: AuditFilter,
+}
+
+/// Edits and completions of the agent, newest first, as json with `?format=json`.
+/// Filtered by `kind`, `client` and `limit`.
+#[tracing::instrument(name = "Agent audit log", skip(state))]
+pub async fn audit_log(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Query(params): Query<AuditParams>,
+) -> Result<Response, AppError> {
     let state_read = state.read().await;
-    let caches = &state_read.env_state.ui_handler;
-    if let Some(cache) = caches.get_state_of_agent(&agent_id) {
-        tracing::info!("Got agent reference");
-        let messages: Vec<MessageRender> = cache.as_ref().iter().map(|m| m.into()).collect();
-        let history = ChatHistory { agent_id, messages };
-        return Html(history.render().unwrap());
-    }
-
-    Html(format!("{}", agent_id))
+    let entries = state_read
+        .env_state(&env_id)?
+        .audit_log(&agent_id, &params.filter)?
+        .ok_or(AppError::NotFound(format!(
+            "No agent with id '{}'",
+            agent_id
+        )))?;
+    Ok(match params.format {
+        WsFormat::Json => Json(entries).into_response(),
+        WsFormat::Html => Html(
+            AuditLogView {
+                env_id,
+                agent_id,
+                entries,
+                filter: params.filter,
+            }
+            .render()?,
+        )
+        .into_response(),
+    })
 }
@@ -1,14 +1,18 @@
 use super::models::LayoutTemplate;
-use crate::SharedState;
+use crate::{agents::AgentList, AppError, SharedState};
 use askama::Template;
 use axum::{extract::State, response::Html};
 
-pub async fn index(State(state): State<SharedState>) -> Html<String> {
+pub async fn index(State(state): State<SharedState>) -> Result<Html<String>, AppError> {
     let state_read = state.read().await;
-    let agent_names = Some(state_read.env_state.agent_names());
+    let environments = state_read
+        .environments
+        .iter()
+        .map(AgentList::from)
+        .collect();
     let template = LayoutTemplate {
-        agent_names,
+        environments: Some(environments),
         path_and_params: None,
     };
-    Html(template.render().unwrap())
+    Ok(Html(template.render()?))
 }
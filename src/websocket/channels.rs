@@ -0,0 +1,69 @@
+use super::protocol::WsResponse;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// Identifies a websocket connection for as long as the app runs
+pub type ConnectionId = u64;
+
+/// Something that happened to an agent, sent to every connection subscribed to it
+#[derive(Debug, Clone)]
+pub struct AgentEvent {
+    /// The connection whose request caused the event, it already got the response directly
+    pub origin: Option<ConnectionId>,
+    pub response: WsResponse,
+}
+
+/// `(env_id, agent_id)`
+pub type AgentKey = (String, String);
+
+/// One broadcast channel per agent, created when the first connection subscribes to it
+#[derive(Debug, Clone, Default)]
+pub struct AgentChannels {
+    senders: Arc<Mutex<HashMap<AgentKey, broadcast::Sender<AgentEvent>>>>,
+}
+
+impl AgentChannels {
+    pub fn subscribe(&self, env_id: &str, agent_id: &str) -> broadcast::Receiver<AgentEvent> {
+        self.senders
+            .lock()
+            .unwrap()
+            .entry((env_id.to_owned(), agent_id.to_owned()))
+            .or_insert_with(|| broadcast::channel(100).0)
+            .subscribe()
+    }
+
+    /// Nothing is sent if no connection ever subscribed to the agent
+    pub fn send(
+        &self,
+        env_id: &str,
+        agent_id: &str,
+        origin: Option<ConnectionId>,
+        response: WsResponse,
+    ) {
+        let senders = self.senders.lock().unwrap();
+        if let Some(tx) = senders.get(&(env_id.to_owned(), agent_id.to_owned())) {
+            let _ = tx.send(AgentEvent { origin, response });
+        }
+    }
+
+    /// Tells the agent's subscribers its active thread changed
+    pub fn history_changed(&self, env_id: &str, agent_id: &str, thread_id: u64) {
+        let response = WsResponse::HistoryChanged {
+            env_id: env_id.to_owned(),
+            agent_id: agent_id.to_owned(),
+            thread_id,
+        };
+        self.send(env_id, agent_id, None, response);
+    }
+
+    /// Closes the agent's channel, its subscribers stop receiving from it
+    pub fn remove(&self, env_id: &str, agent_id: &str) {
+        self.senders
+            .lock()
+            .unwrap()
+            .remove(&(env_id.to_owned(), agent_id.to_owned()));
+    }
+}
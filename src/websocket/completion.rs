@@ -0,0 +1,123 @@
+use super::in_flight::InFlightCompletion;
+use crate::{
+    client::Client,
+    espx_env::{
+        agent_link::AgentLink,
+        usage::{Usage, UsageMeter},
+    },
+    AppError, SharedState,
+};
+use espionox::{
+    agents::memory::Message,
+    language_models::openai::completions::streaming::CompletionStreamStatus,
+};
+use std::future::Future;
+
+/// A completion that was started and still has to be streamed, whichever way its tokens go out
+pub struct PendingReply {
+    link: AgentLink,
+    meter: Option<UsageMeter>,
+    prompt: Message,
+}
+
+/// What was streamed of a reply
+pub struct StreamedReply {
+    pub content: String,
+    pub cancelled: bool,
+    /// `None` if the agent has no threads to meter
+    pub usage: Option<Usage>,
+}
+
+impl PendingReply {
+    /// Meters the completion against the agent's active thread and tells the UI listener who
+    /// the reply is for
+    pub async fn start(
+        state: &SharedState,
+        env_id: &str,
+        agent_id: &str,
+        prompt: Message,
+        client: &Client,
+    ) -> Result<Self, AppError> {
+        let mut state = state.write().await;
+        let env_state = state.env_state_mut(env_id)?;
+        let meter = env_state.usage_meter(agent_id, &prompt);
+        let link = env_state
+            .agent_link(agent_id)?
+            .ok_or(AppError::not_found(format!(
+                "No agent with id '{}'",
+                agent_id
+            )))?;
+        env_state
+            .ui_handler
+            .expect_completion(agent_id, client.as_str());
+        Ok(Self {
+            link,
+            meter,
+            prompt,
+        })
+    }
+
+    pub fn agent_id(&self) -> &str {
+        &self.link.agent_id
+    }
+
+    pub fn link(&self) -> &AgentLink {
+        &self.link
+    }
+
+    /// Prompts the agent and hands each token to `on_token` as it comes.
+    /// A cancelled reply is saved to the agent's cache as far as it got.
+    /// Holds no lock on the `AppState`, other requests go on while the agent replies.
+    pub async fn stream<F, Fut>(
+        self,
+        completion: &mut InFlightCompletion,
+        mut on_token: F,
+    ) -> Result<StreamedReply, AppError>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let agent_id = self.link.agent_id.as_str();
+        let stream = self.link.stream_completion(self.prompt).await?;
+        let mut stream = stream.lock().await;
+
+        let mut content = String::new();
+        let mut cancelled = false;
+        loop {
+            let status = tokio::select! {
+                status = stream.receive(agent_id, self.link.sender()) => status,
+                _ = completion.cancelled() => {
+                    cancelled = true;
+                    None
+                }
+            };
+            match status {
+                Some(CompletionStreamStatus::Working(token)) => {
+                    content.push_str(&token);
+                    on_token(token).await;
+                }
+                Some(CompletionStreamStatus::Finished) => {
+                    tracing::info!("Finished completion stream")
+                }
+                None => break,
+            }
+        }
+
+        if cancelled {
+            tracing::info!("Completion stream cancelled with: {}", content);
+            // What the stream would have pushed to the cache had it finished
+            if !content.is_empty() {
+                self.link
+                    .push_to_cache(Message::new_assistant(&content))
+                    .await
+                    .map_err(|err| err.context("Could not save the cancelled reply"))?;
+            }
+        }
+        let usage = self.meter.map(|meter| meter.finish(&content));
+        Ok(StreamedReply {
+            content,
+            cancelled,
+            usage,
+        })
+    }
+}
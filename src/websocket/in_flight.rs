@@ -0,0 +1,94 @@
+use super::channels::AgentKey;
+use crate::AppError;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+/// Completions being streamed, by agent. Every connection keeps a clone, so a completion can be
+/// cancelled without going through the `AppState`.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    /// An agent stays here until its completion is dropped, cancelled ones included, so nothing
+    /// else starts before a cancelled reply is saved
+    running: Arc<Mutex<HashMap<AgentKey, Running>>>,
+}
+
+#[derive(Debug)]
+struct Running {
+    /// `None` once the completion was told to stop
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// Marks the agent as replying until dropped
+#[derive(Debug)]
+pub struct InFlightCompletion {
+    key: AgentKey,
+    cancelled: oneshot::Receiver<()>,
+    in_flight: InFlight,
+}
+
+impl InFlight {
+    /// An agent only streams one completion at a time
+    pub fn start(&self, env_id: &str, agent_id: &str) -> Result<InFlightCompletion, AppError> {
+        let key = (env_id.to_owned(), agent_id.to_owned());
+        let mut running = self.running.lock().unwrap();
+        if running.contains_key(&key) {
+            return Err(AppError::conflict(format!(
+                "Agent '{}' is already replying",
+                agent_id
+            )));
+        }
+        let (tx, cancelled) = oneshot::channel();
+        running.insert(key.clone(), Running { cancel: Some(tx) });
+        Ok(InFlightCompletion {
+            key,
+            cancelled,
+            in_flight: self.clone(),
+        })
+    }
+
+    /// Whether any completion of the environment is still running
+    pub fn any_in_env(&self, env_id: &str) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|(env, _)| env == env_id)
+    }
+
+    /// The agent stays busy until the cancelled completion is wrapped up
+    pub fn cancel(&self, env_id: &str, agent_id: &str) -> Result<(), AppError> {
+        let key = (env_id.to_owned(), agent_id.to_owned());
+        match self
+            .running
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(|running| running.cancel.take())
+        {
+            Some(tx) => {
+                let _ = tx.send(());
+                Ok(())
+            }
+            None => Err(AppError::bad_request(format!(
+                "Agent '{}' is not replying",
+                agent_id
+            ))),
+        }
+    }
+}
+
+impl InFlightCompletion {
+    /// Resolves once the completion is cancelled
+    pub async fn cancelled(&mut self) {
+        let _ = (&mut self.cancelled).await;
+    }
+}
+
+impl Drop for InFlightCompletion {
+    fn drop(&mut self) {
+        self.in_flight.running.lock().unwrap().remove(&self.key);
+    }
+}
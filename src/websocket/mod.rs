@@ -1,207 +1,491 @@
+pub mod channels;
+pub mod completion;
+pub mod in_flight;
 pub mod models;
-use crate::{AppState, SharedState};
-use anyhow::anyhow;
+pub mod protocol;
+pub mod reply_buffer;
+pub mod sse;
+use crate::client::Client;
+use crate::espx_env::{agent_link::AgentLink, ui_listeners::Regeneration, EnvironmentState};
+use crate::{patches::edit_failed, views::partials::render_history, AppError, SharedState};
 use askama::Template;
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        Query, State,
     },
-    response::{Html, IntoResponse},
-};
-use espionox::{
-    environment::dispatch::ThreadSafeStreamCompletionHandler,
-    language_models::openai::completions::streaming::CompletionStreamStatus,
+    response::IntoResponse,
 };
+use channels::{AgentChannels, AgentEvent, AgentKey, ConnectionId};
+use completion::{PendingReply, StreamedReply};
+use espionox::agents::memory::Message as EspxMessage;
 use futures::{sink::SinkExt, stream::StreamExt};
-use tokio::sync::{broadcast::Sender, RwLockWriteGuard};
-use tracing::{debug, info};
+use in_flight::{InFlight, InFlightCompletion};
+use protocol::{WsFormat, WsRequest, WsResponse};
+use reply_buffer::ReplyBuffer;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{broadcast, mpsc};
+use tracing::debug;
+
+struct WsRequestHandler {
+    req: WsRequest,
+}
 
-#[derive(Debug, Clone, PartialEq)]
-enum WsRequest {
-    PromptAgent { user_input: String },
-    // NewChat { chat_name: String, agent: Agent },
-    Empty,
+impl TryFrom<&str> for WsRequestHandler {
+    type Error = AppError;
+    fn try_from(text: &str) -> Result<Self, Self::Error> {
+        let req = WsRequest::try_from(text)?;
+        tracing::info!("Request deserialized: {:?}", req);
+        Ok(Self { req })
+    }
+}
+
+/// `env_id` and `agent_id` subscribe the connection to that agent as soon as it opens
+#[derive(Deserialize, Debug, Default)]
+pub struct WsParams {
+    #[serde(default)]
+    format: WsFormat,
+    env_id: Option<String>,
+    agent_id: Option<String>,
+    #[serde(default)]
+    mirror: bool,
 }
 
+/// Handed from a connection's receive task to its send task
 #[derive(Debug)]
-struct WsHxTrigger {
-    agent_id: String,
-    // env_id: String,
+enum Outgoing {
+    Respond(WsResponse),
+    Subscribe {
+        agent: AgentKey,
+        rx: broadcast::Receiver<AgentEvent>,
+        mirror: bool,
+    },
 }
 
-struct WsRequestHandler {
-    req: WsRequest,
-    trigger: WsHxTrigger,
+/// The connection a request came in on
+#[derive(Clone, Debug)]
+struct WsConnection {
+    id: ConnectionId,
+    client: Client,
+    outgoing: mpsc::Sender<Outgoing>,
+    in_flight: InFlight,
 }
 
-impl TryFrom<serde_json::Value> for WsRequest {
-    type Error = anyhow::Error;
-    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
-        match value.get("user_input") {
-            Some(user_input) => Ok(WsRequest::PromptAgent {
-                user_input: user_input.to_string().replace('"', ""),
-            }),
-            None => Err(anyhow::anyhow!("No user input field")),
+impl WsConnection {
+    fn new(outgoing: mpsc::Sender<Outgoing>, in_flight: InFlight, client: Client) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            client,
+            outgoing,
+            in_flight,
         }
     }
-}
 
-impl WsHxTrigger {
-    fn try_from_trigger_and_name(hx_trigger: String, hx_trigger_name: String) -> Option<Self> {
-        match hx_trigger.as_str() {
-            "user-input-form" => {
-                if let Some(agent_id) = hx_trigger_name.strip_suffix("-agent-form") {
-                    // if let Some(, agent_id)) = env_and_agent_id
-                    //     .split_once('-')
-                    //     .and_then(|(e, a)| Some((e.to_owned(), a.to_owned())))
-                    // {
-                    //     return Some(Self { agent_id, env_id });
-                    // }
-
-                    return Some(Self {
-                        agent_id: agent_id.to_owned(),
-                    });
-                }
-                None
-            }
-            _ => None,
+    async fn respond(&self, response: WsResponse) {
+        let _ = self.outgoing.send(Outgoing::Respond(response)).await;
+    }
+
+    /// Also sends the response to every other connection subscribed to the agent it's about
+    async fn respond_and_mirror(&self, channels: &AgentChannels, response: WsResponse) {
+        if let Some((env_id, agent_id)) = response.agent() {
+            channels.send(env_id, agent_id, Some(self.id), response.clone());
         }
+        self.respond(response).await;
     }
 }
 
-impl TryFrom<Message> for WsRequestHandler {
-    type Error = anyhow::Error;
-    fn try_from(message: Message) -> Result<Self, Self::Error> {
-        if let Message::Text(text) = message {
-            let mut json_rq: serde_json::Value = serde_json::from_str(&text).unwrap();
-            let headers = json_rq.get("HEADERS").expect("Failed to get headers");
-            let hx_trigger = headers
-                .get("HX-Trigger")
-                .unwrap()
-                .to_string()
-                .replace('"', "");
-            let hx_trigger_name = headers
-                .get("HX-Trigger-Name")
-                .unwrap()
-                .to_string()
-                .replace('"', "");
-
-            let trigger =
-                WsHxTrigger::try_from_trigger_and_name(hx_trigger, hx_trigger_name).unwrap();
-
-            if let Some(obj) = json_rq.as_object_mut() {
-                obj.remove("HEADERS");
-            }
-            let req = WsRequest::try_from(json_rq).expect("Failed to get message from json");
+/// The agent a connection's send task listens to besides its own responses
+struct Subscription {
+    agent: AgentKey,
+    rx: broadcast::Receiver<AgentEvent>,
+    mirror: bool,
+}
 
-            tracing::info!(
-                "Request deserialized: {:?}\n With trigger: {:?}",
-                req,
-                trigger
-            );
+impl Subscription {
+    /// Whether the response is about the agent subscribed to
+    fn is_about_agent(&self, response: &WsResponse) -> bool {
+        response.agent() == Some((self.agent.0.as_str(), self.agent.1.as_str()))
+    }
 
-            return Ok(Self { req, trigger });
+    /// Skips the connection's own events and, unless mirroring, other connections' replies.
+    /// `None` once the agent's channel is closed.
+    async fn next(&mut self, connection_id: ConnectionId) -> Option<WsResponse> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) if event.origin == Some(connection_id) => continue,
+                Ok(event) if event.response.is_mirrored() && !self.mirror => continue,
+                Ok(event) => return Some(event.response),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Connection {} skipped {} events", connection_id, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
         }
-        Err(anyhow!("Incorrect message type"))
     }
 }
 
 impl WsRequestHandler {
-    async fn handle(self, mut state: RwLockWriteGuard<'_, AppState>, tx: Sender<Html<String>>) {
-        match self.req {
-            WsRequest::PromptAgent { user_input } => {
-                if !state.env_state.has_handle() {
-                    state.env_state.spawn().unwrap();
-                }
+    fn streams(&self) -> bool {
+        matches!(
+            self.req,
+            WsRequest::Prompt { .. } | WsRequest::Regenerate { .. }
+        )
+    }
 
-                let ticket = {
-                    let agent_handle = state
-                        .env_state
-                        .get_agent_handle(&self.trigger.agent_id)
-                        .expect("Couldn't get agent handle");
+    /// Errors are sent back to the requesting connection only
+    async fn handle_or_respond(self, state: &SharedState, connection: &WsConnection) {
+        if let Err(err) = self.handle(state, connection).await {
+            err.log();
+            connection.respond(WsResponse::from(&err)).await;
+        }
+    }
 
-                    agent_handle
-                        .request_stream_completion(espionox::agents::memory::Message::new_user(
-                            &user_input,
-                        ))
+    /// Responds to the requesting connection, replies also go to the agent's other subscribers
+    async fn handle(self, state: &SharedState, connection: &WsConnection) -> Result<(), AppError> {
+        match self.req {
+            WsRequest::Ping => connection.respond(WsResponse::Pong).await,
+            WsRequest::Subscribe {
+                env_id,
+                agent_id,
+                mirror,
+            } => {
+                let state = state.read().await;
+                let env_state = state.env_state(&env_id)?;
+                if env_state
+                    .ui_handler
+                    .get_threads_of_agent(&agent_id)
+                    .is_none()
+                {
+                    return Err(AppError::not_found(format!(
+                        "No agent with id '{}'",
+                        agent_id
+                    )));
+                }
+                let rx = state.channels.subscribe(&env_id, &agent_id);
+                let agent = (env_id.to_owned(), agent_id.to_owned());
+                let _ = connection
+                    .outgoing
+                    .send(Outgoing::Subscribe { agent, rx, mirror })
+                    .await;
+                connection
+                    .respond(WsResponse::Subscribed {
+                        env_id,
+                        agent_id,
+                        mirror,
+                    })
+                    .await;
+            }
+            WsRequest::Cancel { env_id, agent_id } => {
+                connection.in_flight.cancel(&env_id, &agent_id)?
+            }
+            WsRequest::Regenerate {
+                env_id,
+                agent_id,
+                keep_variants,
+            } => {
+                let mut completion = connection.in_flight.start(&env_id, &agent_id)?;
+                let (link, channels, regeneration, ack) = {
+                    let mut state = state.write().await;
+                    let channels = state.channels.clone();
+                    let env_state = state.env_state_mut(&env_id)?;
+                    let (regeneration, ack) = env_state
+                        .ui_handler
+                        .take_last_reply(&agent_id, keep_variants, Some(connection.client.as_str()))
+                        .map_err(|err| AppError::bad_request(err).context("Error regenerating"))?;
+                    let link = agent_link(env_state, &agent_id)?;
+                    (link, channels, regeneration, ack)
+                };
+                // Metered once the thread is without the taken reply
+                link.apply_edits(ack)
+                    .await
+                    .map_err(|err| edit_failed(err).context("Error regenerating"))?;
+                let result = async {
+                    let pending = PendingReply::start(
+                        state,
+                        &env_id,
+                        &agent_id,
+                        regeneration.prompt.clone(),
+                        &connection.client,
+                    )
+                    .await?;
+                    stream_to_connection(pending, &mut completion, &env_id, &channels, connection)
                         .await
-                        .expect("Why did I fail to request a stream handle?")
+                }
+                .await;
+                let reply = match result {
+                    Ok(reply) => reply,
+                    Err(err) => {
+                        restore_reply(state, &env_id, &link, regeneration, connection).await;
+                        return Err(err);
+                    }
                 };
+                // A reply cancelled before its first token doesn't take the last one's place
+                if reply.content.is_empty() {
+                    restore_reply(state, &env_id, &link, regeneration, connection).await;
+                } else {
+                    state
+                        .write()
+                        .await
+                        .env_state_mut(&env_id)?
+                        .ui_handler
+                        .finish_regeneration(&agent_id, regeneration, &reply.content);
+                }
+                // The agent is free before the connection hears it's done
+                drop(completion);
+                connection
+                    .respond_and_mirror(&channels, reply.into_response(&env_id, &agent_id))
+                    .await;
+            }
+            WsRequest::Prompt {
+                env_id,
+                agent_id,
+                user_input,
+            } => {
+                let mut completion = connection.in_flight.start(&env_id, &agent_id)?;
+                let prompt = EspxMessage::new_user(&user_input);
+                let channels = state.read().await.channels.clone();
+                let pending =
+                    PendingReply::start(state, &env_id, &agent_id, prompt, &connection.client)
+                        .await?;
+                let reply =
+                    stream_to_connection(pending, &mut completion, &env_id, &channels, connection)
+                        .await?;
+                drop(completion);
+                connection
+                    .respond_and_mirror(&channels, reply.into_response(&env_id, &agent_id))
+                    .await;
+            }
+        }
+        Ok(())
+    }
+}
 
-                let env_handle = state
-                    .env_state
-                    .env_handle()
-                    .expect("Why can't I get env handle?");
+fn agent_link(env_state: &mut EnvironmentState, agent_id: &str) -> Result<AgentLink, AppError> {
+    env_state
+        .agent_link(agent_id)?
+        .ok_or(AppError::not_found(format!(
+            "No agent with id '{}'",
+            agent_id
+        )))
+}
 
-                let noti = env_handle
-                    .wait_for_notification(&ticket)
-                    .await
-                    .expect("Why did I not get Noti?");
+/// Puts back the reply a regeneration took, once the UI's copy of the agent's cache shows whether
+/// the prompt was sent again. Failing to is only logged, the regeneration's own outcome is what
+/// the connection hears about.
+async fn restore_reply(
+    state: &SharedState,
+    env_id: &str,
+    link: &AgentLink,
+    regeneration: Regeneration,
+    connection: &WsConnection,
+) {
+    let result = async {
+        link.sync().await?;
+        let ack = state
+            .write()
+            .await
+            .env_state_mut(env_id)?
+            .ui_handler
+            .restore_reply(
+                &link.agent_id,
+                regeneration,
+                Some(connection.client.as_str()),
+            )?;
+        link.apply_edits(ack).await?;
+        Ok::<_, AppError>(())
+    }
+    .await;
+    if let Err(err) = result {
+        err.context("Could not restore the last reply").log();
+    }
+}
 
-                let stream: &ThreadSafeStreamCompletionHandler =
-                    noti.extract_body().try_into().unwrap();
-                let mut stream = stream.lock().await;
+impl StreamedReply {
+    fn into_response(self, env_id: &str, agent_id: &str) -> WsResponse {
+        let (env_id, agent_id) = (env_id.to_owned(), agent_id.to_owned());
+        match self.cancelled {
+            true => WsResponse::Cancelled {
+                env_id,
+                agent_id,
+                content: self.content,
+            },
+            false => WsResponse::Finished {
+                env_id,
+                agent_id,
+                content: self.content,
+            },
+        }
+    }
+}
 
-                let mut whole_message = String::new();
-                while let Some(status) = stream
-                    .receive(&self.trigger.agent_id, env_handle.new_sender())
-                    .await
-                {
-                    match status {
-                        CompletionStreamStatus::Working(token) => {
-                            whole_message.push_str(&token);
-
-                            let tmplt = models::AssistantMessage::from(whole_message.as_str());
-                            tracing::info!(
-                                "Sending assistant message back to client: {}",
-                                tmplt.render().unwrap()
-                            );
-
-                            let _ = tx.send(Html(tmplt.render().unwrap()));
-                        }
-                        CompletionStreamStatus::Finished => {
-                            tracing::info!("Finished completion stream")
-                        }
+/// Streams the reply to the connection and the agent's mirroring subscribers, rendered as far as
+/// the `ReplyBuffer` can
+async fn stream_to_connection(
+    pending: PendingReply,
+    completion: &mut InFlightCompletion,
+    env_id: &str,
+    channels: &AgentChannels,
+    connection: &WsConnection,
+) -> Result<StreamedReply, AppError> {
+    let agent_id = pending.agent_id().to_owned();
+    let mut buffer = ReplyBuffer::default();
+    pending
+        .stream(completion, |token| {
+            let (env_id, agent_id) = (env_id.to_owned(), agent_id.to_owned());
+            let response = match buffer.push(&token) {
+                Some((content, pending)) => WsResponse::Rendered {
+                    env_id,
+                    agent_id,
+                    content: content.to_owned(),
+                    pending: pending.to_owned(),
+                },
+                None => WsResponse::Token {
+                    env_id,
+                    agent_id,
+                    delta: token,
+                },
+            };
+            connection.respond_and_mirror(channels, response)
+        })
+        .await
+}
+
+/// What a connection is sent: its own responses and the events of the agent it's subscribed to,
+/// encoded in the format it asked for. Drained by the websocket or the server sent event stream.
+struct Outbox {
+    state: SharedState,
+    connection_id: ConnectionId,
+    format: WsFormat,
+    outgoing: mpsc::Receiver<Outgoing>,
+    subscription: Option<Subscription>,
+}
+
+impl Outbox {
+    fn new(
+        state: SharedState,
+        connection_id: ConnectionId,
+        format: WsFormat,
+        outgoing: mpsc::Receiver<Outgoing>,
+    ) -> Self {
+        Self {
+            state,
+            connection_id,
+            format,
+            outgoing,
+            subscription: None,
+        }
+    }
+
+    /// `None` once the connection's requests and subscription are both done with
+    async fn next_frame(&mut self) -> Option<String> {
+        let Self {
+            state,
+            connection_id,
+            format,
+            outgoing,
+            subscription,
+        } = self;
+        loop {
+            let response = tokio::select! {
+                Some(outgoing) = outgoing.recv() => match outgoing {
+                    Outgoing::Respond(response) => response,
+                    Outgoing::Subscribe { agent, rx, mirror } => {
+                        *subscription = Some(Subscription { agent, rx, mirror });
+                        continue;
                     }
-                }
+                },
+                response = async {
+                    match subscription.as_mut() {
+                        Some(subscription) => subscription.next(*connection_id).await,
+                        None => std::future::pending().await,
+                    }
+                } => match response {
+                    Some(response) => response,
+                    None => {
+                        *subscription = None;
+                        continue;
+                    }
+                },
+                else => return None,
+            };
+            // htmx subscribers see a finished reply in the history pushed once it's saved,
+            // swapping in the reply as well would show it twice
+            if *format == WsFormat::Html
+                && matches!(response, WsResponse::Finished { .. })
+                && subscription
+                    .as_ref()
+                    .is_some_and(|s| s.is_about_agent(&response))
+            {
+                continue;
+            }
+            match encode(state, &response, *format).await {
+                Ok(Some(text)) => return Some(text),
+                Ok(None) => continue,
+                Err(err) => err.log(),
             }
-            WsRequest::Empty => {}
         }
     }
 }
 
+/// Like `WsResponse::encode`, with the history a `HistoryChanged` swaps in for htmx clients
+async fn encode(
+    state: &SharedState,
+    response: &WsResponse,
+    format: WsFormat,
+) -> Result<Option<String>, AppError> {
+    match (response, format) {
+        (
+            WsResponse::HistoryChanged {
+                env_id,
+                agent_id,
+                thread_id,
+            },
+            WsFormat::Html,
+        ) => {
+            let state = state.read().await;
+            let history = render_history(
+                state.env_state(env_id)?,
+                agent_id,
+                *thread_id,
+                state.transport,
+            )?;
+            Ok(Some(models::History { history: history.0 }.render()?))
+        }
+        _ => response.encode(format),
+    }
+}
+
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<SharedState>,
+    Query(params): Query<WsParams>,
+    client: Client,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, state))
+    ws.on_upgrade(move |socket| websocket(socket, state, params, client))
 }
 
 // This function deals with a single websocket connection, i.e., a single
 // connected client / user, for which we will spawn two independent tasks (for
 // receiving / sending chat messages).
 #[tracing::instrument(name = "Main websocket function", skip(stream, state))]
-async fn websocket(stream: WebSocket, state: SharedState) {
+async fn websocket(stream: WebSocket, state: SharedState, params: WsParams, client: Client) {
     tracing::info!("Websocket opened");
     // By splitting, we can send and receive at the same time.
     let (mut sender, mut receiver) = stream.split();
 
-    // Username gets set in the receive loop, if it's valid.
-    // Loop until a text message is found.
-
-    let mut rx = state.write().await.tx.subscribe();
+    let (outgoing_tx, outgoing_rx) = mpsc::channel::<Outgoing>(100);
+    let in_flight = state.read().await.in_flight.clone();
+    let connection = WsConnection::new(outgoing_tx, in_flight, client);
+    let mut outbox = Outbox::new(state.clone(), connection.id, params.format, outgoing_rx);
 
-    // Spawn the first task that will receive broadcast messages and send text
+    // Spawn the first task that will receive responses and agent events and send text
     // messages over the websocket to our client.
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
+        while let Some(text) = outbox.next_frame().await {
             // In any websocket error, break loop.
-            if sender
-                .send(Message::Text(format!("{:?}", msg)))
-                .await
-                .is_err()
-            {
+            if sender.send(Message::Text(text)).await.is_err() {
                 tracing::error!("Error in websocket");
                 break;
             }
@@ -209,23 +493,41 @@ async fn websocket(stream: WebSocket, state: SharedState) {
     });
 
     let mut recv_task = tokio::spawn(async move {
+        if let (Some(env_id), Some(agent_id)) = (params.env_id, params.agent_id) {
+            let subscribe = WsRequestHandler {
+                req: WsRequest::Subscribe {
+                    env_id,
+                    agent_id,
+                    mirror: params.mirror,
+                },
+            };
+            subscribe.handle_or_respond(&state, &connection).await;
+        }
+
         while let Some(Ok(message)) = receiver.next().await {
             debug!("MESSAGE RECIEVED BY WS: {:?}", message);
-            let state_write = state.write().await;
-            let tx = state_write.tx.clone();
-
-            let ws_handler = WsRequestHandler::try_from(message).unwrap();
-
-            // let user_message_option: Option<UserMessage> = (&ws_message).try_into().ok();
-            // if let Some(msg) = user_message_option {
-            //     tracing::info!(
-            //         "Sending user message back to client: {}",
-            //         msg.render().unwrap()
-            //     );
-            //     let _ = tx.send(Html(msg.render().unwrap()));
-            // }
-            ws_handler.handle(state_write, tx).await;
-            tracing::info!("Message should have sent");
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let ws_handler = match WsRequestHandler::try_from(text.as_str()) {
+                Ok(ws_handler) => ws_handler,
+                Err(err) => {
+                    err.log();
+                    connection.respond(WsResponse::from(&err)).await;
+                    continue;
+                }
+            };
+            // Streams run on their own so they can be cancelled from this loop
+            if ws_handler.streams() {
+                let (state, connection) = (state.clone(), connection.clone());
+                tokio::spawn(
+                    async move { ws_handler.handle_or_respond(&state, &connection).await },
+                );
+            } else {
+                ws_handler.handle_or_respond(&state, &connection).await;
+            }
         }
     });
 
@@ -236,9 +538,4 @@ async fn websocket(stream: WebSocket, state: SharedState) {
         _ = (&mut send_task) => recv_task.abort(),
         _ = (&mut recv_task) => send_task.abort(),
     };
-
-    // Send "user left" message (similar to "joined" above).
-
-    // Remove username from map so new clients can take it again.
-    // state.user_set.lock().unwrap().remove(&username);
 }
@@ -1,35 +1,59 @@
-use anyhow::anyhow;
 use askama::Template;
 use markdown::to_html;
 
-use super::WsRequest;
-
-#[derive(Template, Debug)]
-#[template(path = "websocket/user_message.html")]
-pub struct UserMessage {
+#[derive(Template)]
+#[template(path = "websocket/assistant_message.html")]
+pub struct AssistantMessage {
     pub content: String,
 }
 
+/// Marked as part of a stream, the agent view only fetches its usage again once it's over
 #[derive(Template)]
-#[template(path = "websocket/assistant_message.html")]
-pub struct AssistantMessage {
+#[template(path = "websocket/token.html")]
+pub struct Token<'a> {
+    pub delta: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "websocket/rendered.html")]
+pub struct Rendered<'a> {
     pub content: String,
+    pub pending: &'a str,
 }
 
-impl TryFrom<&WsRequest> for UserMessage {
-    type Error = anyhow::Error;
-    fn try_from(req: &WsRequest) -> Result<Self, Self::Error> {
-        match req {
-            WsRequest::PromptAgent { user_input } => {
-                let template = UserMessage {
-                    // For some reason to_html appends a newline, so we remove it
-                    content: to_html(&user_input).trim_matches('\n').to_string(),
-                };
-                Ok(template)
-            }
-            WsRequest::Empty => Err(anyhow!("Wrong request type")),
-        }
-    }
+#[derive(Template)]
+#[template(path = "websocket/agent_removed.html")]
+pub struct AgentRemoved {
+    pub env_id: String,
+    pub agent_id: String,
+}
+
+/// The agent's history, pushed to its subscribers whenever its thread changes
+#[derive(Template)]
+#[template(path = "websocket/history.html")]
+pub struct History {
+    pub history: String,
+}
+
+#[derive(Template)]
+#[template(path = "websocket/cancelled.html")]
+pub struct Cancelled<'a> {
+    pub env_id: &'a str,
+    pub agent_id: &'a str,
+}
+
+/// Fills in the connection the agent view posts its prompts for
+#[derive(Template)]
+#[template(path = "websocket/connected.html")]
+pub struct Connected<'a> {
+    pub connection: &'a str,
+}
+
+/// Swapped into the agent view's error element, only sent to the client whose request failed
+#[derive(Template)]
+#[template(path = "websocket/error.html")]
+pub struct WsError<'a> {
+    pub message: &'a str,
 }
 
 impl From<&str> for AssistantMessage {
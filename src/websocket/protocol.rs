@@ -0,0 +1,244 @@
+//! Messages exchanged over `/ws`, every one of them tagged by its `type` and carrying the protocol
+//! version `v`. htmx-ws clients get responses rendered as out of band fragments, clients
+//! connecting with `?format=json` get them as json. Server sent event streams get the same
+//! responses, see `sse`.
+use super::models;
+use crate::AppError;
+use askama::Template;
+use markdown::to_html;
+use serde::{Deserialize, Deserializer, Serialize};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// htmx-ws also sends the form's `HEADERS` along, those are ignored
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WsEnvelope {
+    pub v: u32,
+    #[serde(flatten)]
+    pub request: WsRequest,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsRequest {
+    Prompt {
+        env_id: String,
+        agent_id: String,
+        user_input: String,
+    },
+    Cancel {
+        env_id: String,
+        agent_id: String,
+    },
+    /// Replaces the agent's last reply with a new completion of the prompt it answered
+    Regenerate {
+        env_id: String,
+        agent_id: String,
+        /// Keep the replaced reply as a variant that can be paged back to
+        #[serde(default, deserialize_with = "form_bool")]
+        keep_variants: bool,
+    },
+    /// Only the agent a connection is subscribed to sends it anything besides its own replies
+    Subscribe {
+        env_id: String,
+        agent_id: String,
+        /// Also receive replies to other connections' prompts
+        #[serde(default, deserialize_with = "form_bool")]
+        mirror: bool,
+    },
+    Ping,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsResponse {
+    /// Text the agent just streamed, to append to its reply
+    Token {
+        env_id: String,
+        agent_id: String,
+        delta: String,
+    },
+    /// The reply up to its last complete paragraph, sent instead of the token completing it.
+    /// `pending` is what was streamed of the next paragraph.
+    Rendered {
+        env_id: String,
+        agent_id: String,
+        content: String,
+        pending: String,
+    },
+    /// The reply is complete and saved to the agent's cache
+    Finished {
+        env_id: String,
+        agent_id: String,
+        content: String,
+    },
+    /// The reply was cut short by a `cancel`, `content` is what was saved of it
+    Cancelled {
+        env_id: String,
+        agent_id: String,
+        content: String,
+    },
+    AgentRemoved {
+        env_id: String,
+        agent_id: String,
+    },
+    /// The agent's active thread changed, by an edit or a message reaching its cache. Sent to
+    /// every subscriber of the agent, htmx clients get the thread's whole history swapped in.
+    HistoryChanged {
+        env_id: String,
+        agent_id: String,
+        thread_id: u64,
+    },
+    Subscribed {
+        env_id: String,
+        agent_id: String,
+        mirror: bool,
+    },
+    /// First response of a server sent event stream, prompts posted for it carry `connection`
+    Connected {
+        connection: String,
+    },
+    Pong,
+    Error {
+        status: u16,
+        message: String,
+    },
+}
+
+#[derive(Serialize)]
+struct WsFrame<'a> {
+    v: u32,
+    #[serde(flatten)]
+    response: &'a WsResponse,
+}
+
+/// How a connection wants its responses, picked with the `format` query param of `/ws`
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WsFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+/// htmx-ws sends a checked checkbox's value as a string and leaves out unchecked ones
+pub(super) fn form_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum FormBool {
+        Bool(bool),
+        Text(String),
+    }
+    Ok(match FormBool::deserialize(deserializer)? {
+        FormBool::Bool(b) => b,
+        FormBool::Text(text) => text == "true" || text == "on",
+    })
+}
+
+impl TryFrom<&str> for WsRequest {
+    type Error = AppError;
+    fn try_from(text: &str) -> Result<Self, Self::Error> {
+        let envelope: WsEnvelope = serde_json::from_str(text)
+            .map_err(|err| AppError::bad_request(format!("Malformed request: {}", err)))?;
+        if envelope.v != PROTOCOL_VERSION {
+            return Err(AppError::bad_request(format!(
+                "Unsupported protocol version {}, expected {}",
+                envelope.v, PROTOCOL_VERSION
+            )));
+        }
+        Ok(envelope.request)
+    }
+}
+
+impl From<&AppError> for WsResponse {
+    fn from(err: &AppError) -> Self {
+        Self::Error {
+            status: err.status_code().as_u16(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl WsResponse {
+    /// Replies to a prompt only reach other connections if they opted in to mirroring
+    pub fn is_mirrored(&self) -> bool {
+        matches!(
+            self,
+            Self::Token { .. }
+                | Self::Rendered { .. }
+                | Self::Finished { .. }
+                | Self::Cancelled { .. }
+        )
+    }
+
+    /// `(env_id, agent_id)` of the agent the response is about
+    pub fn agent(&self) -> Option<(&str, &str)> {
+        match self {
+            Self::Token {
+                env_id, agent_id, ..
+            }
+            | Self::Rendered {
+                env_id, agent_id, ..
+            }
+            | Self::Finished {
+                env_id, agent_id, ..
+            }
+            | Self::Cancelled {
+                env_id, agent_id, ..
+            }
+            | Self::AgentRemoved { env_id, agent_id }
+            | Self::HistoryChanged {
+                env_id, agent_id, ..
+            }
+            | Self::Subscribed {
+                env_id, agent_id, ..
+            } => Some((env_id, agent_id)),
+            Self::Connected { .. } | Self::Pong | Self::Error { .. } => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, AppError> {
+        let frame = WsFrame {
+            v: PROTOCOL_VERSION,
+            response: self,
+        };
+        Ok(serde_json::to_string(&frame).map_err(anyhow::Error::from)?)
+    }
+
+    /// `None` for responses htmx has nothing to swap in for. The history a `HistoryChanged` swaps
+    /// in is rendered by the connection, from the app's state.
+    pub fn to_html(&self) -> Result<Option<String>, AppError> {
+        let html = match self {
+            Self::Token { delta, .. } => models::Token { delta }.render()?,
+            Self::Rendered {
+                content, pending, ..
+            } => models::Rendered {
+                content: to_html(content),
+                pending,
+            }
+            .render()?,
+            Self::Finished { content, .. } => {
+                models::AssistantMessage::from(content.as_str()).render()?
+            }
+            Self::Cancelled {
+                env_id, agent_id, ..
+            } => models::Cancelled { env_id, agent_id }.render()?,
+            Self::AgentRemoved { env_id, agent_id } => models::AgentRemoved {
+                env_id: env_id.to_owned(),
+                agent_id: agent_id.to_owned(),
+            }
+            .render()?,
+            Self::Connected { connection } => models::Connected { connection }.render()?,
+            Self::HistoryChanged { .. } | Self::Subscribed { .. } | Self::Pong => return Ok(None),
+            Self::Error { message, .. } => models::WsError { message }.render()?,
+        };
+        Ok(Some(html))
+    }
+
+    pub fn encode(&self, format: WsFormat) -> Result<Option<String>, AppError> {
+        match format {
+            WsFormat::Html => self.to_html(),
+            WsFormat::Json => self.to_json().map(Some),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+const PARAGRAPH_BREAK: &str = "\n\n";
+const CODE_FENCE: &str = "```";
+
+/// Collects a streamed reply. Tokens are sent on as they come, markdown is only rendered once a
+/// paragraph is complete, so a reply isn't rendered again for each of its tokens.
+#[derive(Debug, Default)]
+pub struct ReplyBuffer {
+    content: String,
+    /// Length of the reply up to its last complete paragraph
+    rendered_len: usize,
+}
+
+impl ReplyBuffer {
+    /// Returns the reply up to its last complete paragraph and the rest of it, if the token
+    /// completed a paragraph. A blank line inside a code block doesn't end a paragraph.
+    pub fn push(&mut self, token: &str) -> Option<(&str, &str)> {
+        // A break may be split between the last token and this one
+        let search_from = match self.content.ends_with('\n') {
+            true => self.content.len() - 1,
+            false => self.content.len(),
+        }
+        .max(self.rendered_len);
+        self.content.push_str(token);
+
+        let boundary = self.content[search_from..]
+            .rmatch_indices(PARAGRAPH_BREAK)
+            .map(|(idx, _)| search_from + idx + PARAGRAPH_BREAK.len())
+            .find(|&boundary| {
+                self.content[..boundary]
+                    .matches(CODE_FENCE)
+                    .count()
+                    .is_multiple_of(2)
+            })?;
+        self.rendered_len = boundary;
+        Some(self.content.split_at(boundary))
+    }
+}
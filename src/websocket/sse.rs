@@ -0,0 +1,235 @@
+//! Server sent events, for when websocket upgrades don't make it through. A stream is a connection
+//! like a websocket's that only sends, requests are posted for it by the `connection` token its
+//! first response carries, and go through the same `WsRequestHandler` as websocket requests.
+use super::{
+    protocol::{form_bool, WsFormat, WsRequest, WsResponse},
+    Outbox, WsConnection, WsRequestHandler,
+};
+use crate::{client::Client, AppError, SharedState};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    Form,
+};
+use futures::stream::{self, Stream};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Streams that are open, by their connection token
+#[derive(Debug, Clone, Default)]
+pub struct SseConnections {
+    connections: Arc<Mutex<HashMap<String, WsConnection>>>,
+}
+
+/// Keeps the connection's requests coming until the stream it belongs to is dropped
+struct Registration {
+    token: String,
+    connections: SseConnections,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.token);
+    }
+}
+
+impl SseConnections {
+    /// The token is random rather than the connection's id, only the stream's client may post
+    /// requests for it
+    fn register(&self, connection: WsConnection) -> Registration {
+        let token = Uuid::new_v4().simple().to_string();
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(token.to_owned(), connection);
+        Registration {
+            token,
+            connections: self.clone(),
+        }
+    }
+
+    fn get(&self, token: &str) -> Result<WsConnection, AppError> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(token)
+            .cloned()
+            .ok_or(AppError::not_found(
+                "No open stream with the connection, reload the page",
+            ))
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SseParams {
+    #[serde(default)]
+    format: WsFormat,
+    #[serde(default)]
+    mirror: bool,
+}
+
+/// Subscribes to the agent as a websocket connecting with its `env_id` and `agent_id` does.
+/// Served whether or not the request came from htmx, `EventSource` can't send its headers.
+#[tracing::instrument(name = "Server sent events", skip(state))]
+pub async fn stream_handler(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Query(params): Query<SseParams>,
+    client: Client,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(100);
+    let (in_flight, connections) = {
+        let state = state.read().await;
+        (state.in_flight.clone(), state.sse_connections.clone())
+    };
+    let connection = WsConnection::new(outgoing_tx, in_flight, client);
+    let connection_id = connection.id;
+    let registration = connections.register(connection.clone());
+    connection
+        .respond(WsResponse::Connected {
+            connection: registration.token.to_owned(),
+        })
+        .await;
+    let subscribe = WsRequestHandler {
+        req: WsRequest::Subscribe {
+            env_id,
+            agent_id,
+            mirror: params.mirror,
+        },
+    };
+    subscribe.handle(&state, &connection).await?;
+
+    let outbox = Outbox::new(state.clone(), connection_id, params.format, outgoing_rx);
+    let events = stream::unfold((outbox, registration), |(mut outbox, registration)| async {
+        let frame = outbox.next_frame().await?;
+        // Carriage returns can't be sent in an event's data, new lines are split across fields
+        let event = Event::default().data(frame.replace('\r', ""));
+        Some((Ok(event), (outbox, registration)))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Which stream a posted request is for
+#[derive(Deserialize, Debug)]
+pub struct ConnectionForm {
+    connection: String,
+}
+
+impl ConnectionForm {
+    fn token(&self) -> Result<&str, AppError> {
+        match self.connection.is_empty() {
+            true => Err(AppError::bad_request(
+                "Not connected to the agent's stream yet, try again",
+            )),
+            false => Ok(&self.connection),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PromptForm {
+    #[serde(flatten)]
+    connection: ConnectionForm,
+    user_input: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubscribeForm {
+    #[serde(flatten)]
+    connection: ConnectionForm,
+    #[serde(default, deserialize_with = "form_bool")]
+    mirror: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RegenerateForm {
+    #[serde(flatten)]
+    connection: ConnectionForm,
+    #[serde(default, deserialize_with = "form_bool")]
+    keep_variants: bool,
+}
+
+/// Responses go to the stream, like they would over its websocket. Streaming requests run on
+/// their own so the post returns right away.
+async fn post_request(
+    state: SharedState,
+    connection: &ConnectionForm,
+    req: WsRequest,
+) -> Result<StatusCode, AppError> {
+    let connection = state
+        .read()
+        .await
+        .sse_connections
+        .get(connection.token()?)?;
+    let handler = WsRequestHandler { req };
+    if handler.streams() {
+        tokio::spawn(async move { handler.handle_or_respond(&state, &connection).await });
+    } else {
+        handler.handle_or_respond(&state, &connection).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[tracing::instrument(name = "Post prompt", skip(state, form))]
+pub async fn prompt(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Form(form): Form<PromptForm>,
+) -> Result<StatusCode, AppError> {
+    let req = WsRequest::Prompt {
+        env_id,
+        agent_id,
+        user_input: form.user_input,
+    };
+    post_request(state, &form.connection, req).await
+}
+
+#[tracing::instrument(name = "Post cancel", skip(state))]
+pub async fn cancel(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Form(form): Form<ConnectionForm>,
+) -> Result<StatusCode, AppError> {
+    let req = WsRequest::Cancel { env_id, agent_id };
+    post_request(state, &form, req).await
+}
+
+#[tracing::instrument(name = "Post regenerate", skip(state))]
+pub async fn regenerate(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Form(form): Form<RegenerateForm>,
+) -> Result<StatusCode, AppError> {
+    let req = WsRequest::Regenerate {
+        env_id,
+        agent_id,
+        keep_variants: form.keep_variants,
+    };
+    post_request(state, &form.connection, req).await
+}
+
+/// Switches mirroring of the stream's subscription on or off
+#[tracing::instrument(name = "Post subscribe", skip(state))]
+pub async fn subscribe(
+    State(state): State<SharedState>,
+    Path((env_id, agent_id)): Path<(String, String)>,
+    Form(form): Form<SubscribeForm>,
+) -> Result<StatusCode, AppError> {
+    let req = WsRequest::Subscribe {
+        env_id,
+        agent_id,
+        mirror: form.mirror,
+    };
+    post_request(state, &form.connection, req).await
+}
@@ -0,0 +1,188 @@
+mod common;
+
+use common::{default_config, roles_and_contents, TestApp};
+use espionox::agents::memory::MessageRole;
+use reqwest::{Method, StatusCode};
+
+fn new_agent<'a>(id: &'a str, system_prompt: &'a str) -> [(&'a str, &'a str); 5] {
+    [
+        ("id", id),
+        ("system_prompt", system_prompt),
+        ("provider", "mock"),
+        ("model", "echo"),
+        ("temperature", ""),
+    ]
+}
+
+#[tokio::test]
+async fn created_agents_are_listed_and_reply() {
+    let app = TestApp::spawn().await;
+
+    let (status, list) = app
+        .hx_request(
+            Method::POST,
+            "/default/agents",
+            Some(&new_agent("parrot", "You repeat")),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(list.contains(r#"hx-get="/default/parrot""#));
+    assert!(list.contains(r#"hx-get="/default/echo""#));
+    assert!(app
+        .hx_get("/")
+        .await
+        .contains(r#"hx-get="/default/parrot""#));
+
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+    ws.prompt("parrot", "Polly").await;
+    ws.collect_until(r#""type":"finished""#).await;
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("parrot").await),
+        vec![
+            (MessageRole::System, "You repeat".to_string()),
+            (MessageRole::User, "Polly".to_string()),
+            (MessageRole::Assistant, "Polly".to_string()),
+        ]
+    );
+    // Agents already there keep their caches through the environment being rebuilt
+    assert_eq!(app.agent_cache("echo").await.len(), 1);
+}
+
+#[tokio::test]
+async fn invalid_agents_are_refused_into_the_form_error() {
+    let app = TestApp::spawn().await;
+
+    let (status, body) = app
+        .hx_request(
+            Method::POST,
+            "/default/agents",
+            Some(&new_agent("echo", "")),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("already exists"), "{}", body);
+
+    let mut form = new_agent("gpt", "");
+    form[2] = ("provider", "openai");
+    form[3] = ("model", "gpt5");
+    let (status, body) = app
+        .hx_request(Method::POST, "/default/agents", Some(&form))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("unknown model"), "{}", body);
+
+    let (status, _) = app
+        .hx_request(Method::POST, "/nowhere/agents", Some(&new_agent("a", "")))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleted_agents_are_gone_and_their_viewers_told() {
+    let app = TestApp::spawn().await;
+    let mut viewer = app
+        .ws_connect_to("/ws?format=json&env_id=default&agent_id=echo")
+        .await;
+    viewer.collect_until(r#""type":"subscribed""#).await;
+
+    let (status, list) = app.hx_request(Method::DELETE, "/default/echo", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!list.contains(r#"hx-get="/default/echo""#));
+    assert!(list.contains(r#"hx-get="/default/scripted""#));
+    viewer.collect_until(r#""type":"agent_removed""#).await;
+
+    let (status, _) = app.hx_request(Method::GET, "/default/echo", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = app.hx_request(Method::DELETE, "/default/echo", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // The id is free again, the new agent starts from its own system prompt
+    app.hx_request(
+        Method::POST,
+        "/default/agents",
+        Some(&new_agent("echo", "Born again")),
+    )
+    .await;
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![(MessageRole::System, "Born again".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn recreated_agents_start_a_fresh_audit_log() {
+    let app = TestApp::spawn().await;
+    app.hx_request(
+        Method::PATCH,
+        "/default/echo/threads/1/message_change/0?change=Forgotten",
+        None,
+    )
+    .await;
+    app.hx_request(Method::DELETE, "/default/echo", None).await;
+    app.hx_request(
+        Method::POST,
+        "/default/agents",
+        Some(&new_agent("echo", "Born again")),
+    )
+    .await;
+
+    let audit_log = || async {
+        serde_json::from_str::<serde_json::Value>(
+            &app.hx_get("/default/echo/audit?format=json").await,
+        )
+        .unwrap()
+    };
+    assert_eq!(audit_log().await, serde_json::json!([]));
+    // The deleted agent's entries stay under its thread ids
+    let (status, _) = app
+        .hx_request(
+            Method::PATCH,
+            "/default/echo/threads/2/message_change/0?change=Remembered",
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let log = audit_log().await;
+    assert_eq!(log.as_array().unwrap().len(), 1);
+    assert_eq!(log[0]["thread_id"], 2);
+    assert_eq!(log[0]["after"], "system: Remembered");
+}
+
+#[tokio::test]
+async fn agents_cannot_change_while_a_reply_streams() {
+    let app = TestApp::spawn_with_config(&format!(
+        "{}\n[[agents]]\nid = \"slow\"\nprovider = \"mock\"\nmodel = \"echo\"\ntoken_delay_ms = 100\n",
+        default_config()
+    ))
+    .await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+    ws.prompt("slow", "one two three four").await;
+    ws.collect_until("one").await;
+
+    let (status, body) = app
+        .hx_request(
+            Method::POST,
+            "/default/agents",
+            Some(&new_agent("parrot", "")),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body.contains("replying"), "{}", body);
+    let (status, _) = app.hx_request(Method::DELETE, "/default/echo", None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // The reply was not cut off by the environment being rebuilt
+    let frames = ws.collect_until(r#""type":"finished""#).await;
+    assert!(frames
+        .last()
+        .unwrap()
+        .contains(r#""content":"one two three four""#));
+    let (status, _) = app
+        .hx_request(
+            Method::POST,
+            "/default/agents",
+            Some(&new_agent("parrot", "")),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
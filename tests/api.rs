@@ -0,0 +1,243 @@
+mod common;
+
+use common::{default_config, roles_and_contents, TestApp, ECHO_SYSTEM_PROMPT, SCRIPTED_RESPONSES};
+use espionox::agents::memory::MessageRole;
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+
+fn parse(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| panic!("Not json: {}", body))
+}
+
+#[tokio::test]
+async fn api_lists_agents_and_edits_their_threads() {
+    let app = TestApp::spawn().await;
+
+    let (status, body) = app.api_request(Method::GET, "/api/v1/agents", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let agents = parse(&body);
+    assert_eq!(agents[0]["agent_id"], "echo");
+    assert_eq!(agents[0]["active_thread"], 1);
+    assert_eq!(agents[1]["agent_id"], "scripted");
+
+    let messages = "/api/v1/default/echo/threads/1/messages";
+    let history = parse(&app.api_request(Method::GET, messages, None).await.1);
+    assert_eq!(history["messages"][0]["role"], "system");
+    assert_eq!(history["messages"][0]["content"], ECHO_SYSTEM_PROMPT);
+
+    let edits = "/api/v1/default/echo/threads/1/edits";
+    let push = json!({ "op": "push", "message": { "role": "user", "content": "From the api" } });
+    let (status, body) = app.api_request(Method::POST, edits, Some(push)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse(&body)["messages"][1]["content"], "From the api");
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "From the api".to_string()),
+        ]
+    );
+
+    let stale = json!({ "op": "edit", "index": 1, "content": "Changed", "expected": "stale" });
+    let (status, body) = app.api_request(Method::POST, edits, Some(stale)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(parse(&body)["error"]["status"], 409);
+
+    let missing = "/api/v1/default/echo/threads/7/messages";
+    let (status, body) = app.api_request(Method::GET, missing, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(parse(&body)["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("No thread 7"));
+}
+
+#[tokio::test]
+async fn api_answers_malformed_requests_with_an_error_body() {
+    let app = TestApp::spawn().await;
+
+    let edits = "/api/v1/default/echo/threads/1/edits";
+    let unknown = json!({ "op": "shuffle", "index": 1 });
+    let (status, body) = app.api_request(Method::POST, edits, Some(unknown)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = &parse(&body)["error"];
+    assert_eq!(error["status"], 400);
+    assert!(error["message"]
+        .as_str()
+        .unwrap()
+        .contains("unknown variant `shuffle`"));
+
+    let prompt = "/api/v1/default/echo/prompt";
+    let (status, body) = app.api_request(Method::POST, prompt, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(parse(&body)["error"]["status"], 400);
+
+    let not_a_thread = "/api/v1/default/echo/threads/first/messages";
+    let (status, body) = app.api_request(Method::GET, not_a_thread, None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(parse(&body)["error"]["status"], 400);
+}
+
+#[tokio::test]
+async fn api_prompts_reply_whole_or_streamed() {
+    let app = TestApp::spawn().await;
+
+    let prompt = json!({ "content": "Echo this" });
+    let (status, body) = app
+        .api_request(Method::POST, "/api/v1/default/echo/prompt", Some(prompt))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let reply = parse(&body);
+    assert_eq!(reply["role"], "assistant");
+    assert_eq!(reply["content"], "Echo this");
+    assert_eq!(reply["cancelled"], false);
+
+    let prompt = json!({ "content": "Hello", "stream": true });
+    let (status, body) = app
+        .api_request(
+            Method::POST,
+            "/api/v1/default/scripted/prompt",
+            Some(prompt),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("event:token"), "{}", body);
+    let done = body
+        .split("event:done\ndata:")
+        .nth(1)
+        .expect("Stream should end with a done event");
+    assert_eq!(parse(done.trim())["content"], SCRIPTED_RESPONSES[0]);
+}
+
+#[tokio::test]
+async fn api_prompts_to_an_agent_already_replying_conflict() {
+    let app = TestApp::spawn_with_config(&format!(
+        "{}\n[[agents]]\nid = \"slow\"\nprovider = \"mock\"\nmodel = \"echo\"\ntoken_delay_ms = 100\n",
+        default_config()
+    ))
+    .await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+    ws.prompt("slow", "one two three").await;
+    ws.collect_until("one").await;
+
+    let prompt = json!({ "content": "Me too" });
+    let (status, body) = app
+        .api_request(Method::POST, "/api/v1/default/slow/prompt", Some(prompt))
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body.contains("already replying"), "{}", body);
+
+    // The reply streaming over the websocket goes on
+    ws.collect_until("finished").await;
+}
+
+#[tokio::test]
+async fn openai_chat_completions_go_through_the_agent() {
+    let app = TestApp::spawn().await;
+    let push = json!({ "op": "push", "message": { "role": "user", "content": "In the UI" } });
+    let (status, _) = app
+        .api_request(
+            Method::POST,
+            "/api/v1/default/echo/threads/1/edits",
+            Some(push),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let request = json!({
+        "model": "echo",
+        "messages": [
+            { "role": "user", "content": "Earlier" },
+            { "role": "assistant", "content": "Earlier reply" },
+            { "role": "user", "content": [{ "type": "text", "text": "Echo me" }] },
+        ],
+    });
+    let (status, body) = app
+        .api_request(Method::POST, "/v1/chat/completions", Some(request))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let completion = parse(&body);
+    assert_eq!(completion["object"], "chat.completion");
+    assert_eq!(completion["model"], "echo");
+    assert_eq!(completion["choices"][0]["message"]["role"], "assistant");
+    assert_eq!(completion["choices"][0]["message"]["content"], "Echo me");
+    assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+    let usage = &completion["usage"];
+    assert!(usage["prompt_tokens"].as_u64().unwrap() > 0, "{}", usage);
+    assert_eq!(
+        usage["total_tokens"].as_u64(),
+        Some(
+            usage["prompt_tokens"].as_u64().unwrap() + usage["completion_tokens"].as_u64().unwrap()
+        )
+    );
+
+    // The completion ran on a thread of its own, gone once it's done, the one the UI was on is
+    // as it was
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "In the UI".to_string()),
+        ]
+    );
+    let agents = parse(&app.api_request(Method::GET, "/api/v1/agents", None).await.1);
+    assert_eq!(agents[0]["active_thread"], 1);
+    assert_eq!(
+        agents[0]["threads"].as_array().unwrap().len(),
+        1,
+        "{}",
+        agents
+    );
+    let (status, _) = app
+        .api_request(Method::GET, "/api/v1/default/echo/threads/2/messages", None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let request = json!({
+        "model": "scripted",
+        "messages": [{ "role": "user", "content": "Hello" }],
+        "stream": true,
+    });
+    let (status, body) = app
+        .api_request(Method::POST, "/v1/chat/completions", Some(request))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    let chunks: Vec<Value> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .take_while(|data| *data != "[DONE]")
+        .map(parse)
+        .collect();
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    let content: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(content, SCRIPTED_RESPONSES[0]);
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+    assert!(body.trim_end().ends_with("data:[DONE]"), "{}", body);
+    assert!(roles_and_contents(&app.agent_cache("scripted").await).is_empty());
+
+    let request = json!({ "model": "gpt-4", "messages": [{ "role": "user", "content": "Hi" }] });
+    let (status, body) = app
+        .api_request(Method::POST, "/v1/chat/completions", Some(request))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(parse(&body)["error"]["type"], "invalid_request_error");
+
+    let request = json!({ "model": "echo" });
+    let (status, body) = app
+        .api_request(Method::POST, "/v1/chat/completions", Some(request))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = &parse(&body)["error"];
+    assert_eq!(error["type"], "invalid_request_error");
+    assert!(
+        error["message"].as_str().unwrap().contains("messages"),
+        "{}",
+        body
+    );
+}
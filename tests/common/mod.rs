@@ -0,0 +1,375 @@
+//! Spawns the whole app on a random port with mock agents, so tests need no network or api keys
+// Every test binary compiles this module but uses only part of it
+#![allow(dead_code)]
+
+use bureau_web::{espx_env::config::BureauConfig, routing, AppState, SharedState};
+use espionox::agents::memory::{Message, MessageRole, MessageStack};
+use futures::{SinkExt, StreamExt};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{net::TcpStream, sync::RwLock};
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+pub const ENV_ID: &str = "default";
+pub const ECHO_SYSTEM_PROMPT: &str = "You are an echo";
+pub const SCRIPTED_RESPONSES: [&str; 2] = ["First reply", "Second reply"];
+
+/// `echo` and `scripted` agents in the `default` environment, kept in memory only
+pub fn default_config() -> String {
+    format!(
+        r#"
+database = ":memory:"
+
+[[agents]]
+id = "echo"
+system_prompt = "{}"
+provider = "mock"
+model = "echo"
+
+[[agents]]
+id = "scripted"
+provider = "mock"
+model = "scripted"
+responses = {:?}
+"#,
+        ECHO_SYSTEM_PROMPT, SCRIPTED_RESPONSES
+    )
+}
+
+pub struct TestApp {
+    pub addr: SocketAddr,
+    pub state: SharedState,
+    client: reqwest::Client,
+}
+
+impl TestApp {
+    pub async fn spawn() -> Self {
+        Self::spawn_with_config(&default_config()).await
+    }
+
+    pub async fn spawn_with_config(toml: &str) -> Self {
+        let config = load_config(toml).expect("Test config should be valid");
+        let app_state = AppState::init(&config)
+            .await
+            .expect("Failed to init app state");
+        let state = Arc::new(RwLock::new(app_state));
+
+        let router = routing::main_router().with_state(Arc::clone(&state));
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        Self {
+            addr,
+            state,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Sends the request the way htmx does, returning the status and body
+    pub async fn hx_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        form: Option<&[(&str, &str)]>,
+    ) -> (reqwest::StatusCode, String) {
+        let mut request = self
+            .client
+            .request(method, self.url(path))
+            .header("HX-Request", "true");
+        if let Some(form) = form {
+            request = request.form(form);
+        }
+        let response = request.send().await.expect("Failed to send request");
+        let status = response.status();
+        (status, response.text().await.unwrap())
+    }
+
+    /// Sends the request as if through proxies, the first one having got it from `forwarded_for`
+    pub async fn hx_request_forwarded(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        forwarded_for: &str,
+    ) -> (reqwest::StatusCode, String) {
+        let response = self
+            .client
+            .request(method, self.url(path))
+            .header("HX-Request", "true")
+            .header("X-Forwarded-For", forwarded_for)
+            .send()
+            .await
+            .expect("Failed to send request");
+        let status = response.status();
+        (status, response.text().await.unwrap())
+    }
+
+    pub async fn hx_get(&self, path: &str) -> String {
+        let (status, body) = self.hx_request(reqwest::Method::GET, path, None).await;
+        assert!(status.is_success(), "GET {} returned {}", path, status);
+        body
+    }
+
+    /// Sends the request htmx does once an `hx-prompt` is answered with `prompt`
+    pub async fn hx_prompt(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        prompt: &str,
+    ) -> (reqwest::StatusCode, String) {
+        let response = self
+            .client
+            .request(method, self.url(path))
+            .header("HX-Request", "true")
+            .header("HX-Prompt", prompt)
+            .send()
+            .await
+            .expect("Failed to send request");
+        let status = response.status();
+        (status, response.text().await.unwrap())
+    }
+
+    /// Sends the request as an api client would, with a json body if given
+    pub async fn api_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> (reqwest::StatusCode, String) {
+        let mut request = self.client.request(method, self.url(path));
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body.to_string());
+        }
+        let response = request.send().await.expect("Failed to send request");
+        let status = response.status();
+        (status, response.text().await.unwrap())
+    }
+
+    /// Asks the agent inside the espionox environment for its cache, rather than the UI's copy of it
+    pub async fn agent_cache(&self, agent_id: &str) -> MessageStack {
+        let mut state = self.state.write().await;
+        let env_state = state.env_state_mut(ENV_ID).unwrap();
+        if !env_state.has_handle() {
+            env_state.spawn().unwrap();
+        }
+        let ticket = env_state
+            .get_agent_handle(agent_id)
+            .expect("No agent by the given id")
+            .request_state()
+            .await
+            .unwrap();
+        let noti = env_state
+            .env_handle()
+            .unwrap()
+            .wait_for_notification(&ticket)
+            .await
+            .unwrap();
+        let cache: &MessageStack = noti.extract_body().try_into().unwrap();
+        cache.clone()
+    }
+
+    /// Opens a server sent event stream the way `EventSource` does, without htmx's headers
+    pub async fn sse_connect(&self, path: &str) -> SseClient {
+        let response = self
+            .client
+            .get(self.url(path))
+            .header("Accept", "text/event-stream")
+            .send()
+            .await
+            .expect("Failed to open event stream");
+        assert!(
+            response.status().is_success(),
+            "GET {} returned {}",
+            path,
+            response.status()
+        );
+        SseClient {
+            response,
+            buffer: String::new(),
+        }
+    }
+
+    pub async fn ws_connect(&self) -> WsClient {
+        self.ws_connect_to("/ws").await
+    }
+
+    /// `path` may carry the query params of `/ws`, like `/ws?format=json`
+    pub async fn ws_connect_to(&self, path: &str) -> WsClient {
+        let (stream, _) = tokio_tungstenite::connect_async(format!("ws://{}{}", self.addr, path))
+            .await
+            .expect("Failed to connect websocket");
+        WsClient { stream }
+    }
+}
+
+pub struct WsClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WsClient {
+    /// Sends the envelope htmx-ws sends when the agent view's input form is submitted
+    pub async fn prompt(&mut self, agent_id: &str, user_input: &str) {
+        let envelope = serde_json::json!({
+            "v": 1,
+            "type": "prompt",
+            "env_id": ENV_ID,
+            "agent_id": agent_id,
+            "user_input": user_input,
+            "HEADERS": {
+                "HX-Request": "true",
+                "HX-Trigger": "user-input-form",
+                "HX-Trigger-Name": format!("{}/{}-agent-form", ENV_ID, agent_id),
+                "HX-Target": null,
+                "HX-Current-URL": format!("http://localhost/{}/{}", ENV_ID, agent_id),
+            }
+        });
+        self.send_text(&envelope.to_string()).await;
+    }
+
+    pub async fn cancel(&mut self, agent_id: &str) {
+        let envelope = serde_json::json!({
+            "v": 1,
+            "type": "cancel",
+            "env_id": ENV_ID,
+            "agent_id": agent_id,
+        });
+        self.send_text(&envelope.to_string()).await;
+    }
+
+    pub async fn regenerate(&mut self, agent_id: &str, keep_variants: bool) {
+        let envelope = serde_json::json!({
+            "v": 1,
+            "type": "regenerate",
+            "env_id": ENV_ID,
+            "agent_id": agent_id,
+            "keep_variants": keep_variants,
+        });
+        self.send_text(&envelope.to_string()).await;
+    }
+
+    pub async fn send_text(&mut self, text: &str) {
+        self.stream
+            .send(tungstenite::Message::Text(text.to_string()))
+            .await
+            .expect("Failed to send over websocket");
+    }
+
+    /// Next text frame, `None` if nothing arrives within a couple of seconds
+    pub async fn next_text(&mut self) -> Option<String> {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(2), self.stream.next())
+                .await
+                .ok()??
+                .ok()?;
+            if let tungstenite::Message::Text(text) = message {
+                return Some(text);
+            }
+        }
+    }
+
+    /// Collects text frames until one contains `needle`, panicking if it never shows up
+    pub async fn collect_until(&mut self, needle: &str) -> Vec<String> {
+        let mut frames = vec![];
+        while let Some(text) = self.next_text().await {
+            let found = text.contains(needle);
+            frames.push(text);
+            if found {
+                return frames;
+            }
+        }
+        panic!(
+            "Never received a frame containing {:?}, got {:?}",
+            needle, frames
+        );
+    }
+}
+
+pub struct SseClient {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl SseClient {
+    /// Data of the next event, `None` if nothing arrives within a couple of seconds
+    pub async fn next_data(&mut self) -> Option<String> {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let event: String = self.buffer.drain(..end + 2).collect();
+                let data: Vec<&str> = event
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .collect();
+                // Keep alive comments carry no data
+                if !data.is_empty() {
+                    return Some(data.join("\n"));
+                }
+                continue;
+            }
+            let chunk = tokio::time::timeout(Duration::from_secs(2), self.response.chunk())
+                .await
+                .ok()?
+                .ok()??;
+            self.buffer.push_str(&String::from_utf8_lossy(&chunk));
+        }
+    }
+
+    /// Collects events until one contains `needle`, panicking if it never shows up
+    pub async fn collect_until(&mut self, needle: &str) -> Vec<String> {
+        let mut events = vec![];
+        while let Some(data) = self.next_data().await {
+            let found = data.contains(needle);
+            events.push(data);
+            if found {
+                return events;
+            }
+        }
+        panic!(
+            "Never received an event containing {:?}, got {:?}",
+            needle, events
+        );
+    }
+}
+
+pub fn roles_and_contents(cache: &MessageStack) -> Vec<(MessageRole, String)> {
+    cache
+        .as_ref()
+        .iter()
+        .map(|m: &Message| (m.role.clone(), m.content.to_string()))
+        .collect()
+}
+
+/// Goes through `BureauConfig::from_path` so the config is validated the same way as the app's
+pub fn load_config(toml: &str) -> Result<BureauConfig, anyhow::Error> {
+    let path = config_file(toml);
+    let config = BureauConfig::from_path(&path);
+    let _ = std::fs::remove_file(&path);
+    config
+}
+
+/// A path in the temp dir no other test uses, the caller cleans it up
+pub fn temp_path(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bureau-test-{}.{}", unique_suffix(), extension))
+}
+
+fn config_file(toml: &str) -> PathBuf {
+    let path = temp_path("toml");
+    std::fs::write(&path, toml).expect("Failed to write test config");
+    path
+}
+
+/// Unique enough for concurrently running tests to not share a config file
+fn unique_suffix() -> String {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use bureau_web::AppState;
+use common::{default_config, load_config};
+
+/// The error as `main` prints it before exiting
+fn startup_error(toml: &str) -> String {
+    format!(
+        "{:#}",
+        load_config(toml).expect_err("Config should be refused")
+    )
+}
+
+#[test]
+fn agents_need_a_known_model() {
+    let err = startup_error(
+        r#"
+[[agents]]
+id = "typo"
+provider = "openai"
+model = "gpt5"
+"#,
+    );
+    assert!(err.contains("Environment 'default'"), "{}", err);
+    assert!(err.contains("Agent #1 ('typo')"), "{}", err);
+    assert!(err.contains("unknown model 'gpt5'"), "{}", err);
+}
+
+#[test]
+fn ids_must_be_unique_and_not_reserved() {
+    let err = startup_error(&format!(
+        "{}\n[[agents]]\nid = \"echo\"\nprovider = \"mock\"\nmodel = \"echo\"\n",
+        default_config()
+    ));
+    assert!(
+        err.contains("Agent #3 ('echo'): id is used more than once"),
+        "{}",
+        err
+    );
+
+    let err = startup_error(
+        r#"
+[[environments]]
+id = "api"
+agents = [{ id = "echo", provider = "mock", model = "echo" }]
+"#,
+    );
+    assert!(err.contains("id 'api' is reserved"), "{}", err);
+
+    let err = startup_error(
+        r#"
+[[agents]]
+id = "has space"
+provider = "mock"
+model = "echo"
+"#,
+    );
+    assert!(err.contains("id contains ' '"), "{}", err);
+}
+
+#[test]
+fn unknown_fields_and_empty_configs_are_refused() {
+    let err = startup_error(&format!("{}\ntemprature = 50\n", default_config()));
+    assert!(err.contains("unknown field `temprature`"), "{}", err);
+
+    let err = startup_error("database = \":memory:\"\n");
+    assert!(
+        err.contains("No agents or environments declared"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn missing_api_keys_stop_the_app_from_starting() {
+    let config = load_config(
+        r#"
+database = ":memory:"
+
+[[environments]]
+id = "keyless"
+api_keys = { openai = "BUREAU_TEST_UNSET_KEY" }
+agents = [{ id = "gpt", provider = "openai", model = "gpt4" }]
+"#,
+    )
+    .expect("Config itself is valid");
+    let err = format!(
+        "{:#}",
+        AppState::init(&config)
+            .await
+            .expect_err("App should not start")
+    );
+    assert!(err.contains("Environment 'keyless'"), "{}", err);
+    assert!(err.contains("BUREAU_TEST_UNSET_KEY is not set"), "{}", err);
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::{default_config, roles_and_contents, temp_path, TestApp, ECHO_SYSTEM_PROMPT};
+use espionox::agents::memory::MessageRole;
+use reqwest::Method;
+
+#[tokio::test]
+async fn threads_and_caches_are_restored_after_a_restart() {
+    let db = temp_path("db");
+    let config = default_config().replace(":memory:", db.to_str().unwrap());
+
+    let app = TestApp::spawn_with_config(&config).await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+    ws.prompt("echo", "Remember me").await;
+    ws.collect_until(r#""type":"finished""#).await;
+    // Once the agent answers this, its reply was saved
+    assert_eq!(app.agent_cache("echo").await.len(), 3);
+    app.hx_request(
+        Method::POST,
+        "/default/echo/threads",
+        Some(&[("name", "second")]),
+    )
+    .await;
+    app.hx_request(
+        Method::PATCH,
+        "/default/echo/threads/2/add_message",
+        Some(&[("role", "user"), ("content", "In the second thread")]),
+    )
+    .await;
+
+    let restarted = TestApp::spawn_with_config(&config).await;
+    // The thread active before the restart is the one the agent starts with
+    assert_eq!(
+        roles_and_contents(&restarted.agent_cache("echo").await),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "In the second thread".to_string()),
+        ]
+    );
+    let view = restarted.hx_get("/default/echo").await;
+    assert!(view.contains("main"));
+    assert!(view.contains("second"));
+    let history = restarted.hx_get("/default/echo/threads/1/history").await;
+    assert_eq!(history.matches("<p>Remember me</p>").count(), 2);
+    // Agents not touched before the restart start from their system prompt as usual
+    assert!(restarted.agent_cache("scripted").await.as_ref().is_empty());
+
+    let _ = std::fs::remove_file(&db);
+}
@@ -0,0 +1,356 @@
+mod common;
+
+use bureau_web::{
+    espx_env::ui_listeners::{message_hash, CacheEdit, StackEdit},
+    patches::confirm_edit,
+};
+use common::{roles_and_contents, TestApp, ECHO_SYSTEM_PROMPT};
+use espionox::agents::memory::{Message, MessageRole};
+use reqwest::{Method, StatusCode};
+
+#[tokio::test]
+async fn non_htmx_requests_get_the_layout() {
+    let app = TestApp::spawn().await;
+
+    let body = reqwest::get(app.url("/default/echo"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    assert!(body.contains("<html>"));
+    assert!(body.contains(r#"hx-get="/default/echo?""#));
+}
+
+#[tokio::test]
+async fn index_lists_agents_of_every_environment() {
+    let app = TestApp::spawn().await;
+
+    let body = app.hx_get("/").await;
+
+    assert!(body.contains(r#"id="agent-list-default""#));
+    assert!(body.contains(r#"hx-get="/default/echo""#));
+    assert!(body.contains(r#"hx-get="/default/scripted""#));
+}
+
+#[tokio::test]
+async fn history_renders_the_system_prompt() {
+    let app = TestApp::spawn().await;
+
+    let body = app.hx_get("/default/echo/threads/1/history").await;
+
+    assert!(body.contains("ws-message system-message"));
+    assert!(body.contains(ECHO_SYSTEM_PROMPT));
+}
+
+#[tokio::test]
+async fn add_message_reaches_history_and_agent_cache() {
+    let app = TestApp::spawn().await;
+
+    let (_, body) = app
+        .hx_request(
+            Method::PATCH,
+            "/default/echo/threads/1/add_message",
+            Some(&[("role", "user"), ("content", "Added from the UI")]),
+        )
+        .await;
+    assert!(body.contains("ws-message user-message"));
+    assert!(body.contains("<p>Added from the UI</p>"));
+
+    let cache = app.agent_cache("echo").await;
+    assert_eq!(
+        roles_and_contents(&cache),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "Added from the UI".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn message_change_edits_history_and_agent_cache() {
+    let app = TestApp::spawn().await;
+
+    let (_, body) = app
+        .hx_request(
+            Method::PATCH,
+            "/default/echo/threads/1/message_change/0?change=You%20are%20changed",
+            None,
+        )
+        .await;
+    assert!(body.contains("You are changed"));
+    assert!(!body.contains(ECHO_SYSTEM_PROMPT));
+
+    let cache = app.agent_cache("echo").await;
+    assert_eq!(
+        roles_and_contents(&cache),
+        vec![(MessageRole::System, "You are changed".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn message_change_without_change_param_is_an_error() {
+    let app = TestApp::spawn().await;
+
+    let (status, body) = app
+        .hx_request(
+            Method::PATCH,
+            "/default/echo/threads/1/message_change/0",
+            None,
+        )
+        .await;
+
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+    assert!(body.contains("Error updating cache: no change passed in request"));
+}
+
+#[tokio::test]
+async fn message_delete_removes_from_history_and_agent_cache() {
+    let app = TestApp::spawn().await;
+
+    let (_, body) = app
+        .hx_request(
+            Method::DELETE,
+            "/default/echo/threads/1/message_delete/0",
+            None,
+        )
+        .await;
+    assert!(!body.contains(ECHO_SYSTEM_PROMPT));
+
+    let cache = app.agent_cache("echo").await;
+    assert!(cache.as_ref().is_empty());
+}
+
+#[tokio::test]
+async fn deleted_messages_can_be_undone_and_redone() {
+    let app = TestApp::spawn().await;
+
+    app.hx_request(
+        Method::DELETE,
+        "/default/echo/threads/1/message_delete/0",
+        None,
+    )
+    .await;
+    let (status, history) = app
+        .hx_request(Method::PATCH, "/default/echo/threads/1/undo", None)
+        .await;
+    assert!(status.is_success());
+    assert!(history.contains(ECHO_SYSTEM_PROMPT));
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![(MessageRole::System, ECHO_SYSTEM_PROMPT.to_string())]
+    );
+
+    let (_, history) = app
+        .hx_request(Method::PATCH, "/default/echo/threads/1/redo", None)
+        .await;
+    assert!(!history.contains(ECHO_SYSTEM_PROMPT));
+    assert!(app.agent_cache("echo").await.as_ref().is_empty());
+
+    // Nothing left to redo, the history is returned as it is
+    let (status, _) = app
+        .hx_request(Method::PATCH, "/default/echo/threads/1/redo", None)
+        .await;
+    assert!(status.is_success());
+}
+
+#[tokio::test]
+async fn few_shot_examples_can_be_built_in_place() {
+    let app = TestApp::spawn().await;
+    let thread = "/default/echo/threads/1";
+
+    for content in ["Question", "Answer"] {
+        app.hx_request(
+            Method::PATCH,
+            &format!("{}/add_message", thread),
+            Some(&[("role", "user"), ("content", content)]),
+        )
+        .await;
+    }
+    let (status, _) = app
+        .hx_request(
+            Method::PATCH,
+            &format!("{}/message_insert/1", thread),
+            Some(&[("role", "system"), ("content", "Answer briefly")]),
+        )
+        .await;
+    assert!(status.is_success());
+    app.hx_request(
+        Method::PATCH,
+        &format!("{}/message_role/3", thread),
+        Some(&[("role", "assistant")]),
+    )
+    .await;
+    let (_, history) = app
+        .hx_request(
+            Method::PATCH,
+            &format!("{}/message_move/1?to=0", thread),
+            None,
+        )
+        .await;
+    assert!(history.find("Answer briefly") < history.find(ECHO_SYSTEM_PROMPT));
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![
+            (MessageRole::System, "Answer briefly".to_string()),
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "Question".to_string()),
+            (MessageRole::Assistant, "Answer".to_string()),
+        ]
+    );
+
+    app.hx_request(Method::PATCH, &format!("{}/truncate?len=2", thread), None)
+        .await;
+    assert_eq!(app.agent_cache("echo").await.len(), 2);
+    app.hx_request(Method::PATCH, &format!("{}/clear", thread), None)
+        .await;
+    assert!(app.agent_cache("echo").await.as_ref().is_empty());
+    app.hx_request(Method::PATCH, &format!("{}/undo", thread), None)
+        .await;
+    assert_eq!(app.agent_cache("echo").await.len(), 2);
+
+    let (status, _) = app
+        .hx_request(
+            Method::PATCH,
+            &format!("{}/message_move/0?to=5", thread),
+            None,
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn edits_aimed_at_a_changed_message_are_rejected() {
+    let app = TestApp::spawn().await;
+    let thread = "/default/echo/threads/1";
+    let shown = message_hash(&Message::new_system(ECHO_SYSTEM_PROMPT));
+    assert!(app
+        .hx_get(&format!("{}/history", thread))
+        .await
+        .contains(&shown));
+
+    let (status, _) = app
+        .hx_request(
+            Method::PATCH,
+            &format!("{}/message_change/0?hash={}&change=Be terse", thread, shown),
+            None,
+        )
+        .await;
+    assert!(status.is_success());
+
+    // The page still shows the old hash
+    let (status, _) = app
+        .hx_request(
+            Method::DELETE,
+            &format!("{}/message_delete/0?hash={}", thread, shown),
+            None,
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::CONFLICT);
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![(MessageRole::System, "Be terse".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn edits_out_of_bounds_are_bad_requests() {
+    let app = TestApp::spawn().await;
+
+    let (status, _) = app
+        .hx_request(
+            Method::DELETE,
+            "/default/echo/threads/1/message_delete/3",
+            None,
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+    assert_eq!(app.agent_cache("echo").await.len(), 1);
+    for path in [
+        "/default/echo/threads/1/message_pin/1",
+        "/default/echo/threads/1/message_variant/0?variant=1",
+    ] {
+        let (status, _) = app.hx_request(Method::PATCH, path, None).await;
+        assert_eq!(status, reqwest::StatusCode::BAD_REQUEST, "{}", path);
+    }
+
+    // Only what isn't there at all is not found
+    let (status, _) = app
+        .hx_request(
+            Method::DELETE,
+            "/default/echo/threads/7/message_delete/0",
+            None,
+        )
+        .await;
+    assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn edits_the_agent_turns_down_leave_no_trace() {
+    let app = TestApp::spawn().await;
+    let thread = "/default/echo/threads/1";
+    for content in ["one", "two"] {
+        app.hx_request(
+            Method::PATCH,
+            &format!("{}/add_message", thread),
+            Some(&[("role", "user"), ("content", content)]),
+        )
+        .await;
+    }
+    let one = message_hash(&Message::new_user("one"));
+    let edit = |edit| CacheEdit {
+        agent_id: "echo".to_string(),
+        thread_id: 1,
+        edit,
+        expected: Some(one.clone()),
+    };
+
+    let (removed, changed) = {
+        let mut state = app.state.write().await;
+        let handler = &mut state.env_state_mut("default").unwrap().ui_handler;
+        let removed = handler
+            .push_to_changes(edit(StackEdit::RemoveMessageInCache { idx: 1 }), None)
+            .unwrap();
+        // Checked against the UI's copy, the agent has "two" there by the time it gets it
+        let changed = handler
+            .push_to_changes(
+                edit(StackEdit::EditMessageInCache {
+                    idx: 1,
+                    new_text: "changed".to_string(),
+                }),
+                None,
+            )
+            .unwrap();
+        (removed, changed)
+    };
+    let err = confirm_edit(&app.state, "default", "echo", changed)
+        .await
+        .expect_err("Edit should be turned down");
+    assert_eq!(err.status_code(), StatusCode::CONFLICT);
+    assert!(removed.confirmed().await.is_ok());
+
+    let log: serde_json::Value =
+        serde_json::from_str(&app.hx_get("/default/echo/audit?format=json").await).unwrap();
+    let kinds: Vec<&str> = log
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["kind"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        kinds,
+        vec!["remove_message", "push_message", "push_message"]
+    );
+    // The removal is the latest edit to undo, not the one turned down
+    app.hx_request(Method::PATCH, &format!("{}/undo", thread), None)
+        .await;
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "one".to_string()),
+            (MessageRole::User, "two".to_string()),
+        ]
+    );
+}
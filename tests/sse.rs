@@ -0,0 +1,54 @@
+mod common;
+
+use common::{default_config, TestApp};
+use reqwest::{Method, StatusCode};
+
+#[tokio::test]
+async fn prompts_posted_for_a_stream_are_replied_to_on_it() {
+    let app = TestApp::spawn().await;
+    let mut stream = app.sse_connect("/default/echo/stream?format=json").await;
+
+    let connected: serde_json::Value =
+        serde_json::from_str(&stream.next_data().await.expect("No connected event")).unwrap();
+    assert_eq!(connected["type"], "connected");
+    let connection = connected["connection"].as_str().unwrap().to_owned();
+    assert_eq!(connection.len(), 32, "{}", connection);
+
+    let form = [
+        ("connection", connection.as_str()),
+        ("user_input", "Over server sent events"),
+    ];
+    let (status, _) = app
+        .hx_request(Method::POST, "/default/echo/prompt", Some(&form))
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let events = stream.collect_until(r#""type":"finished""#).await;
+    assert!(events.iter().any(|e| e.contains(r#""type":"token""#)));
+    assert!(events
+        .last()
+        .unwrap()
+        .contains(r#""content":"Over server sent events""#));
+
+    let form = [("connection", "123456789"), ("user_input", "Anyone?")];
+    let (status, _) = app
+        .hx_request(Method::POST, "/default/echo/prompt", Some(&form))
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn sse_transport_has_the_agent_view_use_htmx_sse() {
+    let app =
+        TestApp::spawn_with_config(&format!("transport = \"sse\"\n{}", default_config())).await;
+
+    let view = app.hx_get("/default/echo").await;
+    assert!(view.contains(r#"sse-connect="/default/echo/stream""#));
+    assert!(view.contains(r#"hx-post="/default/echo/prompt""#));
+    assert!(!view.contains(r#"hx-ext="ws""#));
+
+    let mut stream = app.sse_connect("/default/echo/stream").await;
+    let connected = stream.next_data().await.expect("No connected event");
+    assert!(connected.contains(r#"id="sse-connection""#));
+    assert!(connected.contains(r#"hx-swap-oob="true""#));
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use common::{roles_and_contents, TestApp, ECHO_SYSTEM_PROMPT};
+use espionox::agents::memory::MessageRole;
+use reqwest::{Method, StatusCode};
+
+/// Id of the thread the agent view marks as active
+fn active_thread(view: &str) -> u64 {
+    let (_, rest) = view
+        .split_once("active-thread\">")
+        .expect("No active thread in the view");
+    let (_, rest) = rest.split_once("/threads/").unwrap();
+    rest.split('/').next().unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn threads_can_be_created_switched_and_renamed() {
+    let app = TestApp::spawn().await;
+
+    let (status, view) = app
+        .hx_request(
+            Method::POST,
+            "/default/echo/threads",
+            Some(&[("name", "side")]),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(active_thread(&view), 2);
+    app.hx_request(
+        Method::PATCH,
+        "/default/echo/threads/2/add_message",
+        Some(&[("role", "user"), ("content", "Only on the side")]),
+    )
+    .await;
+    assert_eq!(app.agent_cache("echo").await.len(), 2);
+
+    let (_, view) = app
+        .hx_request(Method::PATCH, "/default/echo/threads/1/switch", None)
+        .await;
+    assert_eq!(active_thread(&view), 1);
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![(MessageRole::System, ECHO_SYSTEM_PROMPT.to_string())]
+    );
+    let side = app.hx_get("/default/echo/threads/2/history").await;
+    assert!(side.contains("Only on the side"));
+
+    let (status, view) = app
+        .hx_prompt(Method::PATCH, "/default/echo/threads/2/rename", "renamed")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(view.contains("renamed"));
+    assert!(!view.contains("side"));
+
+    let (status, body) = app
+        .hx_request(
+            Method::POST,
+            "/default/echo/threads",
+            Some(&[("name", "  ")]),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("Thread name cannot be empty"));
+    let (status, _) = app
+        .hx_request(Method::PATCH, "/default/echo/threads/9/switch", None)
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn deleting_the_active_thread_switches_to_another() {
+    let app = TestApp::spawn().await;
+    app.hx_request(
+        Method::PATCH,
+        "/default/echo/threads/1/add_message",
+        Some(&[("role", "user"), ("content", "Kept in main")]),
+    )
+    .await;
+    app.hx_request(
+        Method::POST,
+        "/default/echo/threads",
+        Some(&[("name", "doomed")]),
+    )
+    .await;
+
+    let (status, view) = app
+        .hx_request(Method::DELETE, "/default/echo/threads/2", None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(active_thread(&view), 1);
+    assert!(!view.contains("doomed"));
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "Kept in main".to_string()),
+        ]
+    );
+
+    let (status, body) = app
+        .hx_request(Method::DELETE, "/default/echo/threads/1", None)
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("only thread"));
+    let (status, _) = app
+        .hx_request(Method::DELETE, "/default/echo/threads/2", None)
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn ids_of_deleted_threads_are_not_given_out_again() {
+    let app = TestApp::spawn().await;
+    app.hx_request(
+        Method::POST,
+        "/default/echo/threads",
+        Some(&[("name", "doomed")]),
+    )
+    .await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+    ws.prompt("echo", "Counted against the doomed thread").await;
+    ws.collect_until(r#""type":"finished""#).await;
+    app.hx_request(Method::DELETE, "/default/echo/threads/2", None)
+        .await;
+
+    let (_, view) = app
+        .hx_request(
+            Method::POST,
+            "/default/echo/threads",
+            Some(&[("name", "fresh")]),
+        )
+        .await;
+    assert_eq!(active_thread(&view), 3);
+    let usage: serde_json::Value =
+        serde_json::from_str(&app.hx_get("/default/echo/usage?format=json").await).unwrap();
+    let threads = usage["threads"].as_array().unwrap();
+    assert_eq!(threads.len(), 1, "{}", usage);
+    assert_eq!(threads[0]["thread_id"], 2);
+    assert!(threads[0]["name"].is_null());
+}
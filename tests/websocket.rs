@@ -0,0 +1,594 @@
+mod common;
+
+use bureau_web::websocket::in_flight::InFlight;
+use common::{roles_and_contents, TestApp, ECHO_SYSTEM_PROMPT, SCRIPTED_RESPONSES};
+use espionox::agents::memory::MessageRole;
+use reqwest::{Method, StatusCode};
+use std::time::Duration;
+
+#[tokio::test]
+async fn prompt_streams_reply_as_oob_fragments() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect().await;
+
+    ws.prompt("echo", "hello there agent").await;
+    let frames = ws.collect_until("hello there agent").await;
+
+    // Tokens are appended as they come, the reply is rendered once it's finished
+    assert_eq!(
+        frames,
+        vec![
+            "<span id=\"assistant-pending\" hx-swap-oob=\"beforeend\" data-stream>hello </span>",
+            "<span id=\"assistant-pending\" hx-swap-oob=\"beforeend\" data-stream>there </span>",
+            "<span id=\"assistant-pending\" hx-swap-oob=\"beforeend\" data-stream>agent</span>",
+            "<div id=\"assistant-message\" hx-swap-oob=\"innerHTML\"><p>hello there agent</p>\n</div>",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn replies_are_rendered_a_paragraph_at_a_time() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    // The mock streams a token per word
+    ws.prompt("echo", "one\n\n ``` code\n\n more ``` \n\n two")
+        .await;
+    let frames: Vec<serde_json::Value> = ws
+        .collect_until("\"finished\"")
+        .await
+        .iter()
+        .map(|f| serde_json::from_str(f).unwrap())
+        .collect();
+
+    let rendered: Vec<(&str, &str)> = frames
+        .iter()
+        .filter(|f| f["type"] == "rendered")
+        .map(|f| {
+            (
+                f["content"].as_str().unwrap(),
+                f["pending"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    // The blank line inside the code block doesn't end a paragraph
+    assert_eq!(
+        rendered,
+        vec![
+            ("one\n\n", " "),
+            ("one\n\n ``` code\n\n more ``` \n\n", " "),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn prompt_and_reply_end_up_in_agent_cache_and_history() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect().await;
+
+    ws.prompt("echo", "remember me").await;
+    ws.collect_until("remember me").await;
+
+    let cache = app.agent_cache("echo").await;
+    assert_eq!(
+        roles_and_contents(&cache),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "remember me".to_string()),
+            (MessageRole::Assistant, "remember me".to_string()),
+        ]
+    );
+
+    let history = app.hx_get("/default/echo/threads/1/history").await;
+    assert_eq!(history.matches("<p>remember me</p>").count(), 2);
+}
+
+#[tokio::test]
+async fn scripted_agent_replies_in_turn() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect().await;
+
+    ws.prompt("scripted", "one").await;
+    ws.collect_until(SCRIPTED_RESPONSES[0]).await;
+    ws.prompt("scripted", "two").await;
+    ws.collect_until(SCRIPTED_RESPONSES[1]).await;
+
+    let cache = app.agent_cache("scripted").await;
+    assert_eq!(
+        roles_and_contents(&cache),
+        vec![
+            (MessageRole::User, "one".to_string()),
+            (MessageRole::Assistant, SCRIPTED_RESPONSES[0].to_string()),
+            (MessageRole::User, "two".to_string()),
+            (MessageRole::Assistant, SCRIPTED_RESPONSES[1].to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn ui_edits_are_seen_by_the_next_prompt() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect().await;
+
+    // The scripted agent picks its reply by counting assistant messages
+    app.hx_request(
+        Method::PATCH,
+        "/default/scripted/threads/1/add_message",
+        Some(&[("role", "assistant"), ("content", "Added from the UI")]),
+    )
+    .await;
+    ws.prompt("scripted", "which turn is it").await;
+    let frames = ws.collect_until(SCRIPTED_RESPONSES[1]).await;
+
+    assert!(frames.iter().all(|f| !f.contains(SCRIPTED_RESPONSES[0])));
+}
+
+#[tokio::test]
+async fn malformed_request_gets_an_error_frame_and_keeps_the_socket() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect().await;
+
+    ws.send_text("not json").await;
+    let frame = ws.next_text().await.expect("No error frame");
+    assert!(frame.contains("id=\"ws-error\""));
+    assert!(frame.contains("Malformed request"));
+
+    ws.prompt("echo", "still here").await;
+    ws.collect_until("still here").await;
+}
+
+#[tokio::test]
+async fn prompts_keep_their_double_quotes() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect().await;
+
+    ws.prompt("echo", r#"say "hi""#).await;
+    // espionox trims quotes off of every streamed token, so only the prompt keeps them
+    ws.collect_until("say hi").await;
+
+    let cache = app.agent_cache("echo").await;
+    assert_eq!(
+        roles_and_contents(&cache)[1],
+        (MessageRole::User, r#"say "hi""#.to_string())
+    );
+}
+
+#[tokio::test]
+async fn json_clients_get_tagged_responses() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    ws.send_text(r#"{"v": 1, "type": "ping"}"#).await;
+    let pong: serde_json::Value = serde_json::from_str(&ws.next_text().await.unwrap()).unwrap();
+    assert_eq!(pong, serde_json::json!({"v": 1, "type": "pong"}));
+
+    ws.send_text(r#"{"v": 1, "type": "dance"}"#).await;
+    let error: serde_json::Value = serde_json::from_str(&ws.next_text().await.unwrap()).unwrap();
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["status"], 400);
+    assert!(error["message"]
+        .as_str()
+        .unwrap()
+        .contains("unknown variant `dance`"));
+}
+
+#[tokio::test]
+async fn replies_only_reach_the_requester_and_mirroring_viewers() {
+    let app = TestApp::spawn().await;
+    let mut requester = app.ws_connect().await;
+    let mut viewer = app
+        .ws_connect_to("/ws?format=json&env_id=default&agent_id=echo")
+        .await;
+    let mut mirror = app
+        .ws_connect_to("/ws?format=json&env_id=default&agent_id=echo&mirror=true")
+        .await;
+    let mut other = app
+        .ws_connect_to("/ws?format=json&env_id=default&agent_id=scripted&mirror=true")
+        .await;
+    for ws in [&mut viewer, &mut mirror, &mut other] {
+        ws.collect_until("\"subscribed\"").await;
+    }
+
+    requester.prompt("echo", "just for me").await;
+    requester.collect_until("just for me").await;
+    mirror.collect_until("just for me").await;
+
+    // Viewers only hear that the history changed
+    let mut viewer_frames = vec![];
+    while let Some(frame) = viewer.next_text().await {
+        viewer_frames.push(frame);
+    }
+    assert!(!viewer_frames.is_empty());
+    assert!(viewer_frames
+        .iter()
+        .all(|f| f.contains("\"history_changed\"") && !f.contains("just for me")));
+    assert_eq!(other.next_text().await, None);
+}
+
+#[tokio::test]
+async fn cancel_stops_the_reply_and_keeps_what_was_streamed() {
+    let app = TestApp::spawn_with_config(&slow_agent_config()).await;
+    let mut ws = app.ws_connect().await;
+    let prompt = "one two three four five six seven eight nine ten";
+
+    ws.prompt("slow", prompt).await;
+    ws.collect_until("one").await;
+    ws.cancel("slow").await;
+    ws.collect_until("Generation stopped").await;
+
+    let cache = app.agent_cache("slow").await;
+    let (role, reply) = roles_and_contents(&cache).pop().unwrap();
+    assert_eq!(role, MessageRole::Assistant);
+    assert!(reply.starts_with("one"));
+    assert!(
+        reply.len() < prompt.len(),
+        "Reply was not cut short: {}",
+        reply
+    );
+}
+
+#[test]
+fn agents_stay_busy_until_their_cancelled_completion_is_wrapped_up() {
+    let in_flight = InFlight::default();
+    let completion = in_flight.start("default", "slow").unwrap();
+    let other = in_flight.start("default", "echo").unwrap();
+
+    in_flight.cancel("default", "slow").unwrap();
+    // The cancelled reply is still to be saved
+    let busy = in_flight.start("default", "slow").unwrap_err();
+    assert_eq!(busy.status_code(), StatusCode::CONFLICT);
+    assert!(in_flight.cancel("default", "slow").is_err());
+
+    drop(completion);
+    in_flight.start("default", "slow").unwrap();
+    assert!(in_flight.any_in_env("default"));
+    drop(other);
+    assert!(!in_flight.any_in_env("default"));
+}
+
+#[tokio::test]
+async fn regenerated_replies_can_be_paged_back_to() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    ws.prompt("echo", "say it again").await;
+    ws.collect_until("\"finished\"").await;
+    let (status, _) = app
+        .hx_request(
+            Method::PATCH,
+            "/default/echo/threads/1/message_change/2?change=edited",
+            None,
+        )
+        .await;
+    assert!(status.is_success());
+
+    ws.regenerate("echo", true).await;
+    ws.collect_until("\"finished\"").await;
+    let history = app.hx_get("/default/echo/threads/1/history").await;
+    assert!(history.contains("2/2"));
+    assert_eq!(
+        roles_and_contents(&app.agent_cache("echo").await),
+        vec![
+            (MessageRole::System, ECHO_SYSTEM_PROMPT.to_string()),
+            (MessageRole::User, "say it again".to_string()),
+            (MessageRole::Assistant, "say it again".to_string()),
+        ]
+    );
+
+    let (status, history) = app
+        .hx_request(
+            Method::PATCH,
+            "/default/echo/threads/1/message_variant/2?variant=0",
+            None,
+        )
+        .await;
+    assert!(status.is_success());
+    assert!(history.contains("1/2"));
+    assert!(history.contains("<p>edited</p>"));
+    let (_, reply) = roles_and_contents(&app.agent_cache("echo").await)
+        .pop()
+        .unwrap();
+    assert_eq!(reply, "edited");
+}
+
+#[tokio::test]
+async fn regenerations_stopped_before_any_token_keep_the_last_reply() {
+    let app = TestApp::spawn_with_config(&slow_agent_config()).await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    ws.prompt("slow", "one two").await;
+    ws.collect_until("\"finished\"").await;
+    let before = roles_and_contents(&app.agent_cache("slow").await);
+    assert_eq!(
+        before,
+        vec![
+            (MessageRole::User, "one two".to_string()),
+            (MessageRole::Assistant, "one two".to_string()),
+        ]
+    );
+
+    ws.regenerate("slow", true).await;
+    // Well before the first token, which takes 100ms
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    ws.cancel("slow").await;
+    ws.collect_until("\"cancelled\"").await;
+
+    let history = app.hx_get("/default/slow/threads/1/history").await;
+    assert!(!history.contains("1/2"), "{}", history);
+    assert_eq!(roles_and_contents(&app.agent_cache("slow").await), before);
+}
+
+#[tokio::test]
+async fn edits_to_a_reply_since_replaced_cannot_be_undone() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    ws.prompt("echo", "say it").await;
+    ws.collect_until("\"finished\"").await;
+    app.hx_request(
+        Method::PATCH,
+        "/default/echo/threads/1/message_change/2?change=edited",
+        None,
+    )
+    .await;
+    ws.regenerate("echo", false).await;
+    ws.collect_until("\"finished\"").await;
+
+    let (status, body) = app
+        .hx_request(Method::PATCH, "/default/echo/threads/1/undo", None)
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body.contains("can no longer be undone"), "{}", body);
+    let (_, reply) = roles_and_contents(&app.agent_cache("echo").await)
+        .pop()
+        .unwrap();
+    assert_eq!(reply, "say it");
+}
+
+#[tokio::test]
+async fn regenerating_without_a_reply_is_an_error() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    ws.regenerate("echo", false).await;
+    let frame = ws.collect_until("\"error\"").await.pop().unwrap();
+    assert!(frame.contains("Last message is not a reply to a prompt"));
+}
+
+#[tokio::test]
+async fn other_requests_go_on_while_an_agent_replies() {
+    let app = TestApp::spawn_with_config(&slow_agent_config()).await;
+    let mut slow = app.ws_connect_to("/ws?format=json").await;
+    let mut echo = app.ws_connect_to("/ws?format=json").await;
+
+    slow.prompt("slow", "one two three four five six seven eight nine ten")
+        .await;
+    slow.collect_until("one").await;
+
+    let history = tokio::time::timeout(
+        Duration::from_millis(500),
+        app.hx_get("/default/echo/threads/1/history"),
+    )
+    .await
+    .expect("History was blocked by the reply");
+    assert!(history.contains(ECHO_SYSTEM_PROMPT));
+
+    echo.prompt("echo", "meanwhile").await;
+    echo.collect_until("\"finished\"").await;
+    let frames = slow.collect_until("\"finished\"").await;
+    assert!(frames.iter().any(|f| f.contains("ten")));
+}
+
+/// A `slow` agent echoing a token every 100ms on top of the default agents
+fn slow_agent_config() -> String {
+    format!(
+        r#"{}
+[[agents]]
+id = "slow"
+provider = "mock"
+model = "echo"
+token_delay_ms = 100
+"#,
+        common::default_config()
+    )
+}
+
+#[tokio::test]
+async fn completions_add_to_the_usage_of_their_agent_and_thread() {
+    let config = format!(
+        "{}\n[prices]\necho = {{ prompt = 1000000.0, completion = 2000000.0 }}\n",
+        common::default_config()
+    );
+    let app = TestApp::spawn_with_config(&config).await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    // 12 characters, so 3 tokens each way on top of the messages' overhead
+    ws.prompt("echo", "count me too").await;
+    ws.collect_until("\"finished\"").await;
+
+    let usage: serde_json::Value =
+        serde_json::from_str(&app.hx_get("/default/echo/usage?format=json").await).unwrap();
+    assert_eq!(usage["model"], "echo");
+    assert_eq!(usage["usage"]["completions"], 1);
+    assert_eq!(usage["usage"]["completion_tokens"], 3);
+    let prompt_tokens = usage["usage"]["prompt_tokens"].as_u64().unwrap();
+    assert!(prompt_tokens > 3, "System prompt was not counted");
+    assert_eq!(usage["cost"], (prompt_tokens + 2 * 3) as f64);
+    assert_eq!(usage["threads"][0]["name"], "main");
+    assert_eq!(usage["threads"][0]["usage"], usage["usage"]);
+
+    let html = app.hx_get("/default/echo/usage").await;
+    assert!(html.contains("1 completions"));
+}
+
+/// The finished reply of the frames collected in json
+fn finished_content(frames: &[String]) -> String {
+    let finished: serde_json::Value = serde_json::from_str(frames.last().unwrap()).unwrap();
+    finished["content"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn only_the_context_window_is_sent_to_the_agent() {
+    let config = format!(
+        r#"{}
+[[agents]]
+id = "recall"
+provider = "mock"
+model = "recall"
+context = {{ max_tokens = 12 }}
+"#,
+        common::default_config()
+    );
+    let app = TestApp::spawn_with_config(&config).await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    ws.prompt("recall", "first message here").await;
+    ws.collect_until("\"finished\"").await;
+    ws.prompt("recall", "second").await;
+    let reply = finished_content(&ws.collect_until("\"finished\"").await);
+    assert_eq!(reply, "user: second");
+    assert_eq!(app.agent_cache("recall").await.len(), 4);
+    let history = app.hx_get("/default/recall/threads/1/history").await;
+    assert_eq!(history.matches("outside-window\"").count(), 3);
+    // Only the window counts: 5 + 4 tokens for the first prompt, 2 + 4 for the second alone
+    let usage: serde_json::Value =
+        serde_json::from_str(&app.hx_get("/default/recall/usage?format=json").await).unwrap();
+    assert_eq!(usage["usage"]["prompt_tokens"], 15);
+
+    // Pinned messages are kept however old they are
+    let (status, _) = app
+        .hx_request(
+            Method::PATCH,
+            "/default/recall/threads/1/message_pin/0",
+            None,
+        )
+        .await;
+    assert!(status.is_success());
+    ws.prompt("recall", "third").await;
+    let reply = finished_content(&ws.collect_until("\"finished\"").await);
+    assert_eq!(reply, "user: first message here\nuser: third");
+    assert_eq!(app.agent_cache("recall").await.len(), 6);
+}
+
+#[tokio::test]
+async fn dropped_messages_can_be_summarized() {
+    let config = format!(
+        r#"{}
+[[agents]]
+id = "recall"
+provider = "mock"
+model = "recall"
+context = {{ max_tokens = 12, policy = "summarize" }}
+"#,
+        common::default_config()
+    );
+    let app = TestApp::spawn_with_config(&config).await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    ws.prompt("recall", "first message here").await;
+    ws.collect_until("\"finished\"").await;
+    ws.prompt("recall", "second").await;
+    let reply = finished_content(&ws.collect_until("\"finished\"").await);
+    assert_eq!(
+        reply,
+        "system: Summary of earlier messages: first message here / user: first message here\nuser: second"
+    );
+}
+
+#[tokio::test]
+async fn edits_and_completions_are_logged_with_their_client() {
+    let app = TestApp::spawn().await;
+    let mut ws = app.ws_connect_to("/ws?format=json").await;
+
+    app.hx_request(
+        Method::PATCH,
+        "/default/echo/threads/1/message_change/0?change=You%20are%20audited",
+        None,
+    )
+    .await;
+    ws.prompt("echo", "hello").await;
+    ws.collect_until("\"finished\"").await;
+
+    let log: serde_json::Value =
+        serde_json::from_str(&app.hx_get("/default/echo/audit?format=json").await).unwrap();
+    let kinds: Vec<&str> = log
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["kind"].as_str().unwrap())
+        .collect();
+    // Newest first
+    assert_eq!(kinds, vec!["completion", "edit_message"]);
+    assert_eq!(log[0]["after"], "user: hello");
+    assert_eq!(log[1]["before"], format!("system: {}", ECHO_SYSTEM_PROMPT));
+    assert_eq!(log[1]["after"], "system: You are audited");
+    assert_eq!(log[0]["client"], "127.0.0.1");
+    assert_eq!(log[1]["client"], "127.0.0.1");
+
+    let edits: serde_json::Value = serde_json::from_str(
+        &app.hx_get("/default/echo/audit?format=json&kind=edit_message&client=")
+            .await,
+    )
+    .unwrap();
+    assert_eq!(edits.as_array().unwrap().len(), 1);
+
+    let html = app.hx_get("/default/echo/audit?kind=completion").await;
+    assert_eq!(html.matches("class=\"audit-entry\"").count(), 1);
+}
+
+/// Client of the latest entry of the echo agent's audit log
+async fn last_audited_client(app: &TestApp) -> String {
+    let log: serde_json::Value =
+        serde_json::from_str(&app.hx_get("/default/echo/audit?format=json").await).unwrap();
+    log[0]["client"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn forwarded_addresses_are_only_believed_from_trusted_proxies() {
+    let change = "/default/echo/threads/1/message_change/0?change=Forwarded";
+    let app = TestApp::spawn().await;
+    app.hx_request_forwarded(Method::PATCH, change, "10.0.0.1")
+        .await;
+    assert_eq!(last_audited_client(&app).await, "127.0.0.1");
+
+    let app = TestApp::spawn_with_config(&common::default_config().replace(
+        "database = \":memory:\"",
+        "database = \":memory:\"\ntrusted_proxies = [\"127.0.0.1\", \"10.0.0.9\"]",
+    ))
+    .await;
+    // The client can make up the start of the header, the trusted proxies only append to it
+    app.hx_request_forwarded(Method::PATCH, change, "6.6.6.6, 10.0.0.1, 10.0.0.9")
+        .await;
+    assert_eq!(last_audited_client(&app).await, "10.0.0.1");
+}
+
+#[tokio::test]
+async fn viewers_get_the_history_pushed_when_it_changes() {
+    let app = TestApp::spawn().await;
+    let mut viewer = app.ws_connect_to("/ws?env_id=default&agent_id=echo").await;
+    let mut requester = app.ws_connect().await;
+
+    app.hx_request(
+        Method::PATCH,
+        "/default/echo/threads/1/add_message",
+        Some(&[("role", "user"), ("content", "Added in another tab")]),
+    )
+    .await;
+    let frame = viewer
+        .collect_until("Added in another tab")
+        .await
+        .pop()
+        .unwrap();
+    assert!(frame.starts_with("<div id=\"chat-history\" hx-swap-oob=\"innerHTML\">"));
+
+    requester.prompt("echo", "pushed reply").await;
+    requester.collect_until("pushed reply").await;
+    // Once with the prompt, then with the prompt and its reply
+    loop {
+        let frame = viewer.collect_until("pushed reply").await.pop().unwrap();
+        if frame.matches("<p>pushed reply</p>").count() == 2 {
+            assert!(frame.contains("Added in another tab"));
+            break;
+        }
+    }
+}